@@ -0,0 +1,167 @@
+//! Stand-in for `koge29_h8-3069f_emulator` used by the integration tests.
+//!
+//! It accepts the same command line as the real emulator, but the file given
+//! with `--elf` is a script instead of a program. Every script line is sent to
+//! the simulator as is, except for these directives:
+//!
+//! - `# ...` and empty lines are ignored.
+//! - `@version <n>` and `@capabilities <a,b,...>` change the handshake answer.
+//!   They must appear before any other line.
+//! - `@no-hello` as the first line skips answering the handshake, like an
+//!   emulator from before it.
+//! - `@sleep <ms>` waits before the next line.
+//! - `@expect <line>` waits until the simulator sends `<line>`.
+//! - `@print <text>` and `@eprint <text>` write `<text>` to the process's
+//!   own stdout and stderr.
+//! - `@exit <code>` exits immediately with `<code>`.
+//!
+//! The process exits with 0 after the last line.
+
+use h8pks::emulator::protocol::{Capability, EmulatorEvent, HostCommand, PROTOCOL_VERSION};
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    process::exit,
+    thread,
+    time::Duration,
+};
+
+const ALL_CAPABILITIES: &[Capability] = &[
+    Capability::IoPort,
+    Capability::Sync,
+    Capability::Stdin,
+    Capability::Pause,
+    Capability::Memory,
+    Capability::Speed,
+];
+
+struct Args {
+    script: String,
+    port: u16,
+}
+
+fn parse_args() -> Args {
+    let mut script = None;
+    let mut port = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--elf" => script = args.next(),
+            "--port" => port = args.next().and_then(|p| p.parse().ok()),
+            _ => (),
+        }
+    }
+    match (script, port) {
+        (Some(script), Some(port)) => Args { script, port },
+        _ => {
+            eprintln!("usage: fake_emulator --elf <script> --port <port>");
+            exit(2);
+        }
+    }
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn send(&mut self, line: &[u8]) {
+        if self.writer.write_all(&[line, b"\n"].concat()).is_err() {
+            exit(1);
+        }
+    }
+
+    fn recv(&mut self) -> Option<String> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line.trim_end_matches('\n').to_string()),
+        }
+    }
+}
+
+fn main() {
+    let args = parse_args();
+    let script = match std::fs::read_to_string(&args.script) {
+        Ok(script) => script,
+        Err(e) => {
+            eprintln!("failed to read script {}: {}", args.script, e);
+            exit(2);
+        }
+    };
+    let mut lines = script.lines().peekable();
+
+    let mut version = PROTOCOL_VERSION;
+    let mut capabilities = ALL_CAPABILITIES.to_vec();
+    let mut hello = true;
+    while let Some(line) = lines.peek() {
+        if *line == "@no-hello" {
+            hello = false;
+        } else if let Some(v) = line.strip_prefix("@version ") {
+            version = v.trim().parse().expect("invalid @version");
+        } else if let Some(list) = line.strip_prefix("@capabilities ") {
+            capabilities = list
+                .split(',')
+                .filter_map(|c| Capability::from_name(c.trim()))
+                .collect();
+        } else {
+            break;
+        }
+        lines.next();
+    }
+
+    let listener = TcpListener::bind(("127.0.0.1", args.port)).expect("failed to bind port");
+    let (stream, _) = listener.accept().expect("failed to accept connection");
+    let mut conn = Connection {
+        reader: BufReader::new(stream.try_clone().unwrap()),
+        writer: stream,
+    };
+
+    match conn.recv().map(|line| HostCommand::decode(&line)) {
+        Some(Ok(HostCommand::Hello { .. })) => (),
+        other => {
+            eprintln!("expected hello, got {:?}", other);
+            exit(1);
+        }
+    }
+    if hello {
+        conn.send(
+            &EmulatorEvent::Hello {
+                version,
+                capabilities,
+            }
+            .encode(),
+        );
+    }
+
+    for line in lines {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(ms) = line.strip_prefix("@sleep ") {
+            thread::sleep(Duration::from_millis(
+                ms.trim().parse().expect("invalid @sleep"),
+            ));
+        } else if let Some(expected) = line.strip_prefix("@expect ") {
+            loop {
+                match conn.recv() {
+                    Some(received) if received == expected => break,
+                    Some(_) => continue,
+                    None => {
+                        eprintln!("connection closed while expecting {:?}", expected);
+                        exit(1);
+                    }
+                }
+            }
+        } else if let Some(text) = line.strip_prefix("@print ") {
+            println!("{}", text);
+        } else if let Some(text) = line.strip_prefix("@eprint ") {
+            eprintln!("{}", text);
+        } else if let Some(code) = line.strip_prefix("@exit ") {
+            exit(code.trim().parse().expect("invalid @exit"));
+        } else {
+            conn.send(line.as_bytes());
+        }
+    }
+}
@@ -0,0 +1,76 @@
+//! How the peripherals of the practice kit are wired to the H8/3069F.
+
+/// Push switches are on bits 0-1 (active low), toggle switches on bits 2-3.
+pub const SWITCH_PORT: u8 = 0x5;
+/// Value of `SWITCH_PORT` with every switch released or off.
+pub const SWITCHES_RELEASED: u8 = 0x3;
+/// Number of wired switches of each kind.
+pub const SWITCH_COUNT: usize = 2;
+
+pub const LED_PORT: u8 = 0xb;
+
+/// Upper nibble is the digit, lower nibble selects which digits show it.
+pub const SEVEN_SEG_PORT: u8 = 0x4;
+/// How long a digit stays lit after it was last driven, unless it was driven
+/// by the latest write.
+pub const SEVEN_SEG_HOLD_STATES: usize = 200_000 * 3;
+
+pub fn set_toggle_switch(port5: u8, switch: usize, on: bool) -> u8 {
+    let bit = 1 << (switch + 2);
+    if on {
+        port5 | bit
+    } else {
+        port5 & !bit
+    }
+}
+
+pub fn set_push_switch(port5: u8, switch: usize, pressed: bool) -> u8 {
+    let bit = 1 << switch;
+    if pressed {
+        port5 & !bit
+    } else {
+        port5 | bit
+    }
+}
+
+/// Whether a `SEVEN_SEG_PORT` write at `written` still lights its digits at
+/// `state`, given a later write.
+pub fn seven_seg_held(written: usize, state: usize) -> bool {
+    written + SEVEN_SEG_HOLD_STATES > state
+}
+
+/// Digits shown at `state` by `SEVEN_SEG_PORT` writes up to it, oldest first.
+/// The latest write stays lit until the next one.
+pub fn seven_seg_digits<'a>(
+    writes: impl IntoIterator<Item = &'a (u8, usize)>,
+    state: usize,
+) -> [Option<u8>; 4] {
+    let mut writes = writes.into_iter().peekable();
+    let mut digits = [None; 4];
+    while let Some((value, written)) = writes.next() {
+        if writes.peek().is_some() && !seven_seg_held(*written, state) {
+            continue;
+        }
+        for (i, digit) in digits.iter_mut().enumerate() {
+            if (value >> i) & 1 == 1 {
+                *digit = Some(value >> 4);
+            }
+        }
+    }
+    digits
+}
+
+/// Ports with something of the kit wired to them.
+pub const PORTS: [u8; 3] = [SEVEN_SEG_PORT, SWITCH_PORT, LED_PORT];
+
+pub fn port_index(port: u8) -> Option<usize> {
+    PORTS.iter().position(|p| *p == port)
+}
+
+/// A value written to one of `PORTS`, by the program or by the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortWrite {
+    pub state: usize,
+    pub port: u8,
+    pub value: u8,
+}
@@ -1,16 +1,62 @@
 use anyhow::Result;
 use eframe::egui;
-use std::path::PathBuf;
+use protocol::{Capability, EmulatorEvent, HostCommand, PROTOCOL_VERSION};
+use session::{Direction, SessionRecorder};
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
     net::{
         tcp::{OwnedReadHalf, OwnedWriteHalf},
         TcpStream,
     },
     sync::mpsc::{channel, Receiver, Sender},
     task::JoinHandle,
+    time::Instant,
 };
 
+mod error;
+pub mod protocol;
+pub mod session;
+mod termination;
+
+pub use error::EmulatorError;
+pub use termination::{RunSummary, TerminationReason};
+
 pub const EMULATOR_FILE_NAME: &str = "koge29_h8-3069f_emulator";
+pub const EMULATOR_HOST: &str = "127.0.0.1";
+pub const DEFAULT_EMULATOR_PORT: u16 = 12345;
+
+/// Clock of the H8/3069F on the practice kit. One state is one clock cycle.
+pub const CLOCK_FREQUENCY: usize = 20_000_000;
+
+/// Capabilities implemented by this simulator.
+pub const HOST_CAPABILITIES: &[Capability] = &[
+    Capability::IoPort,
+    Capability::Sync,
+    Capability::Stdin,
+    Capability::Pause,
+    Capability::Speed,
+];
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_INITIAL_BACKOFF: Duration = Duration::from_millis(10);
+const CONNECT_MAX_BACKOFF: Duration = Duration::from_millis(500);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Capabilities of a replayed session. Commands have no effect on it, so
+/// only `sync` is kept for the speed display.
+const REPLAY_CAPABILITIES: &[Capability] = &[Capability::Sync];
+
+const MAX_SENT_MESSAGES: usize = 1000;
+
+/// How long a stopped emulator may take to exit before it is killed.
+pub const STOP_TIMEOUT: Duration = Duration::from_secs(3);
 
 pub fn get_emulator_dir_path() -> Result<PathBuf> {
     let mut path = std::env::current_exe()?;
@@ -55,74 +101,373 @@ pub fn check_version() -> Option<String> {
     return None;
 }
 
+/// Asks the OS for an unused port on the loopback interface.
+///
+/// The port is released again before returning, so the emulator can bind it.
+pub fn find_free_port() -> std::io::Result<u16> {
+    let listener = std::net::TcpListener::bind((EMULATOR_HOST, 0))?;
+    Ok(listener.local_addr()?.port())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+/// A line printed by the emulator process itself, not by the emulated program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogLine {
+    pub stream: LogStream,
+    pub text: String,
+}
+
 pub struct Emulator {
-    pub process: tokio::process::Child,
+    /// `None` when attached to an emulator that was started elsewhere.
+    pub process: Option<tokio::process::Child>,
     pub socket_receiver_handle: JoinHandle<()>,
-    message_rx: Receiver<String>,
+    capabilities: Vec<Capability>,
+    message_rx: Receiver<Vec<u8>>,
     message_tx: Sender<String>,
+    recorder: Arc<Mutex<Option<SessionRecorder>>>,
+    /// Commands sent since the last `pop_sent_messages`.
+    sent_messages: Mutex<VecDeque<String>>,
+    replaying: bool,
 }
 
 impl Emulator {
+    /// Output of the emulator process is sent to `log_tx` line by line, also
+    /// when starting fails. Every message is recorded to `session_path` if
+    /// given.
     pub async fn execute(
         elf_path: String,
         elf_args: String,
+        port: Option<u16>,
+        session_path: Option<&Path>,
+        log_tx: Sender<LogLine>,
+        ctx: egui::Context,
+    ) -> Result<Emulator, EmulatorError> {
+        let emulator_path = get_emulator_path().map_err(|e| {
+            EmulatorError::Spawn(std::io::Error::new(std::io::ErrorKind::NotFound, e))
+        })?;
+        Emulator::execute_with_path(
+            emulator_path,
+            elf_path,
+            elf_args,
+            port,
+            session_path,
+            log_tx,
+            ctx,
+        )
+        .await
+    }
+
+    /// Same as `execute`, but runs the emulator executable at `emulator_path`
+    /// instead of the downloaded one.
+    pub async fn execute_with_path(
+        emulator_path: PathBuf,
+        elf_path: String,
+        elf_args: String,
+        port: Option<u16>,
+        session_path: Option<&Path>,
+        log_tx: Sender<LogLine>,
         ctx: egui::Context,
-    ) -> Result<Emulator, String> {
-        let emulator_path = get_emulator_path().unwrap();
+    ) -> Result<Emulator, EmulatorError> {
+        let port = match port {
+            Some(port) => port,
+            None => find_free_port().map_err(EmulatorError::Port)?,
+        };
+        log::info!("Emulator port: {}", port);
+
         let arg = "-a=".to_string() + &elf_args;
-        let process = tokio::process::Command::new(emulator_path)
+        let port_arg = port.to_string();
+        let mut process = tokio::process::Command::new(emulator_path)
             .kill_on_drop(true)
-            .args(["--elf", &elf_path, "-w", "-s", arg.as_str()])
+            .args([
+                "--elf",
+                &elf_path,
+                "-w",
+                "-s",
+                "--port",
+                &port_arg,
+                arg.as_str(),
+            ])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
             .spawn()
-            .expect("Failed to start emulator.");
+            .map_err(EmulatorError::Spawn)?;
 
-        let stream: TcpStream;
-        loop {
-            let _stream = TcpStream::connect("127.0.0.1:12345").await;
-            match _stream {
-                Ok(s) => {
-                    stream = s;
-                    break;
+        if let Some(stdout) = process.stdout.take() {
+            Emulator::spawn_log_worker(stdout, LogStream::Stdout, log_tx.clone(), ctx.clone());
+        }
+        if let Some(stderr) = process.stderr.take() {
+            Emulator::spawn_log_worker(stderr, LogStream::Stderr, log_tx, ctx.clone());
+        }
+
+        let addr = format!("{}:{}", EMULATOR_HOST, port);
+        let stream = Emulator::connect(Some(&mut process), &addr).await?;
+        Emulator::start(stream, Some(process), session_path, ctx).await
+    }
+
+    /// Connects to an emulator that is already listening on `addr`.
+    ///
+    /// The emulator process is left running when the connection is dropped.
+    pub async fn attach(
+        addr: String,
+        session_path: Option<&Path>,
+        ctx: egui::Context,
+    ) -> Result<Emulator, EmulatorError> {
+        log::info!("Attaching to emulator at {}", addr);
+        let stream = Emulator::connect(None, &addr).await?;
+        Emulator::start(stream, None, session_path, ctx).await
+    }
+
+    async fn start(
+        mut stream: TcpStream,
+        process: Option<tokio::process::Child>,
+        session_path: Option<&Path>,
+        ctx: egui::Context,
+    ) -> Result<Emulator, EmulatorError> {
+        let capabilities = Emulator::handshake(&mut stream).await?;
+        log::info!(
+            "Emulator capabilities: {:?} (protocol v{})",
+            capabilities,
+            PROTOCOL_VERSION
+        );
+
+        // Created before the receive worker, so no message is missed.
+        let recorder =
+            session_path.and_then(|path| match SessionRecorder::create(path, &capabilities) {
+                Ok(recorder) => {
+                    log::info!("Recording session to {}", path.display());
+                    Some(recorder)
                 }
                 Err(e) => {
-                    if process.id().is_none() {
-                        return Err(e.to_string());
-                    }
+                    log::error!("Failed to record session to {}: {}", path.display(), e);
+                    None
                 }
-            }
-        }
-
+            });
+        let recorder = Arc::new(Mutex::new(recorder));
         let (socket_reader, socket_writer) = stream.into_split();
         let (message_rx, socket_receiver_handle) =
-            Emulator::spawn_receive_worker(socket_reader, ctx);
+            Emulator::spawn_receive_worker(socket_reader, recorder.clone(), ctx);
         let message_tx = Emulator::spawn_send_worker(socket_writer);
 
         Ok(Emulator {
             process,
             socket_receiver_handle,
+            capabilities,
+            message_rx,
+            message_tx,
+            recorder,
+            sent_messages: Mutex::new(VecDeque::new()),
+            replaying: false,
+        })
+    }
+
+    /// Plays back the messages received in a recorded session with their
+    /// original timing. Commands other than `cmd:stop` are ignored.
+    pub async fn replay(path: PathBuf, ctx: egui::Context) -> Result<Emulator, EmulatorError> {
+        log::info!("Replaying session {}", path.display());
+        let (header, records) =
+            session::read_session(&path).map_err(EmulatorError::InvalidSession)?;
+        let capabilities = protocol::decode_capabilities(&header.capabilities)
+            .into_iter()
+            .filter(|c| REPLAY_CAPABILITIES.contains(c))
+            .collect();
+
+        let (message_tx, mut command_rx) = channel::<String>(32);
+        let (replay_tx, message_rx) = channel(64);
+        let socket_receiver_handle = tokio::spawn(async move {
+            let start = Instant::now();
+            for record in records.into_iter().filter(|r| r.dir == Direction::In) {
+                let at = start + Duration::from_secs_f64(record.time.max(0.0));
+                tokio::select! {
+                    _ = tokio::time::sleep_until(at) => (),
+                    _ = Emulator::wait_for_stop(&mut command_rx) => break,
+                }
+                if replay_tx.send(record.message()).await.is_err() {
+                    break;
+                }
+                ctx.request_repaint();
+            }
+            ctx.request_repaint();
+        });
+
+        Ok(Emulator {
+            process: None,
+            socket_receiver_handle,
+            capabilities,
             message_rx,
             message_tx,
+            recorder: Arc::new(Mutex::new(None)),
+            sent_messages: Mutex::new(VecDeque::new()),
+            replaying: true,
         })
     }
 
-    fn spawn_send_worker(socket_writer: OwnedWriteHalf) -> Sender<String> {
-        let (message_tx, mut message_rx) = channel(32);
+    /// Returns on `cmd:stop` or when the `Emulator` is dropped.
+    async fn wait_for_stop(command_rx: &mut Receiver<String>) {
+        let stop = HostCommand::Stop.encode();
+        while let Some(command) = command_rx.recv().await {
+            if command == stop {
+                return;
+            }
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.lock().unwrap().is_some()
+    }
+
+    /// Retries connecting with exponential backoff until the emulator starts
+    /// listening, exits, or `CONNECT_TIMEOUT` elapses.
+    async fn connect(
+        mut process: Option<&mut tokio::process::Child>,
+        addr: &str,
+    ) -> Result<TcpStream, EmulatorError> {
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        let mut backoff = CONNECT_INITIAL_BACKOFF;
+        loop {
+            let err = match TcpStream::connect(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => e,
+            };
+            if let Some(process) = process.as_mut() {
+                if let Some(status) = process.try_wait()? {
+                    return Err(EmulatorError::ProcessExited(status));
+                }
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(EmulatorError::ConnectTimeout {
+                    addr: addr.to_string(),
+                    timeout: CONNECT_TIMEOUT,
+                    source: err,
+                });
+            }
+            tokio::time::sleep(backoff.min(deadline - now)).await;
+            backoff = (backoff * 2).min(CONNECT_MAX_BACKOFF);
+        }
+    }
+
+    /// Exchanges protocol version and capabilities with the emulator.
+    ///
+    /// Returns the capabilities supported by both sides.
+    async fn handshake(stream: &mut TcpStream) -> Result<Vec<Capability>, EmulatorError> {
+        let hello = HostCommand::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: HOST_CAPABILITIES.to_vec(),
+        };
+        stream.write_all((hello.encode() + "\n").as_bytes()).await?;
+
+        let line = tokio::time::timeout(HANDSHAKE_TIMEOUT, Emulator::read_line(stream))
+            .await
+            .map_err(|_| EmulatorError::HandshakeTimeout(HANDSHAKE_TIMEOUT))??;
+
+        match EmulatorEvent::decode(line.as_bytes()) {
+            Ok(EmulatorEvent::Hello {
+                version,
+                capabilities,
+            }) => {
+                if version != PROTOCOL_VERSION {
+                    return Err(EmulatorError::IncompatibleVersion {
+                        emulator: version,
+                        simulator: PROTOCOL_VERSION,
+                    });
+                }
+                Ok(capabilities
+                    .into_iter()
+                    .filter(|c| HOST_CAPABILITIES.contains(c))
+                    .collect())
+            }
+            Ok(_) => Err(EmulatorError::UnsupportedProtocol {
+                simulator: PROTOCOL_VERSION,
+                line,
+            }),
+            Err(e) => Err(EmulatorError::InvalidHandshake(e.to_string())),
+        }
+    }
+
+    /// Reads a single line without buffering past it, so the rest of the
+    /// stream is left for the receive worker.
+    async fn read_line(stream: &mut TcpStream) -> std::io::Result<String> {
+        let mut line = Vec::new();
+        loop {
+            match stream.read_u8().await? {
+                b'\n' => break,
+                ch => line.push(ch),
+            }
+        }
+        Ok(String::from_utf8_lossy(&line).into_owned())
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    pub fn is_attached(&self) -> bool {
+        self.process.is_none() && !self.replaying
+    }
+
+    pub fn is_replay(&self) -> bool {
+        self.replaying
+    }
+
+    /// Returns true once the connection is closed and the spawned process, if
+    /// any, has exited.
+    pub fn is_finished(&mut self) -> bool {
+        if !self.socket_receiver_handle.is_finished() {
+            return false;
+        }
+        match self.process.as_mut() {
+            Some(process) => !matches!(process.try_wait(), Ok(None)),
+            None => true,
+        }
+    }
+
+    /// Exit status of the spawned process, if it has exited.
+    pub fn exit_status(&mut self) -> Option<ExitStatus> {
+        self.process
+            .as_mut()
+            .and_then(|process| process.try_wait().ok().flatten())
+    }
+
+    fn spawn_log_worker<R: AsyncRead + Unpin + Send + 'static>(
+        reader: R,
+        stream: LogStream,
+        log_tx: Sender<LogLine>,
+        ctx: egui::Context,
+    ) {
+        tokio::spawn(async move {
+            let mut reader = BufReader::new(reader);
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                match reader.read_until(b'\n', &mut line).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => (),
+                }
+                let text = String::from_utf8_lossy(&line)
+                    .trim_end_matches(['\r', '\n'])
+                    .to_string();
+                if log_tx.send(LogLine { stream, text }).await.is_err() {
+                    break;
+                }
+                ctx.request_repaint();
+            }
+        });
+    }
+
+    /// Ends once the connection fails, which makes further sends fail.
+    fn spawn_send_worker(mut socket_writer: OwnedWriteHalf) -> Sender<String> {
+        let (message_tx, mut message_rx) = channel::<String>(32);
         tokio::spawn(async move {
             while let Some(message) = message_rx.recv().await {
-                let _msg: String = message + "\n";
-                let str_bytes = _msg.as_bytes();
-                let mut written_bytes = 0;
-                loop {
-                    socket_writer.writable().await.unwrap();
-                    match socket_writer.try_write(str_bytes) {
-                        Ok(n) => {
-                            written_bytes += n;
-                        }
-                        Err(_) => {}
-                    }
-                    if written_bytes == str_bytes.len() {
-                        break;
-                    }
+                let line = protocol::escape(&message) + "\n";
+                if let Err(e) = socket_writer.write_all(line.as_bytes()).await {
+                    log::error!("Failed to send to emulator: {}", e);
+                    break;
                 }
             }
         });
@@ -132,51 +477,45 @@ impl Emulator {
 
     fn spawn_receive_worker(
         socket_reader: OwnedReadHalf,
+        recorder: Arc<Mutex<Option<SessionRecorder>>>,
         ctx: egui::Context,
-    ) -> (Receiver<String>, JoinHandle<()>) {
+    ) -> (Receiver<Vec<u8>>, JoinHandle<()>) {
         let (message_tx, message_rx) = channel(64);
         let handle = tokio::spawn(async move {
-            let mut message: Vec<u8> = Vec::new();
+            let mut reader = BufReader::new(socket_reader);
+            let mut line = Vec::new();
             loop {
-                let mut received = vec![0; 128];
-                socket_reader.readable().await.unwrap();
-                match socket_reader.try_read(&mut received) {
-                    Ok(n) => {
-                        if n == 0 {
-                            break;
+                line.clear();
+                match reader.read_until(b'\n', &mut line).await {
+                    Ok(0) => break,
+                    Ok(_) => {
+                        // A line cut off by EOF is still delivered.
+                        if line.last() == Some(&b'\n') {
+                            line.pop();
                         }
-                        received.truncate(n);
-
-                        for ch in received {
-                            if ch == b'\n' {
-                                let message_string = String::from_utf8(message.clone())
-                                    .unwrap()
-                                    .to_string()
-                                    .replace("\\n", "\n")
-                                    .replace("\\\\", "\\");
-                                message_tx.send(message_string).await.unwrap();
-                                message.clear();
-                            } else {
-                                message.push(ch);
-                            }
+                        let message = protocol::unescape(&line);
+                        if let Some(recorder) = recorder.lock().unwrap().as_mut() {
+                            recorder.record(Direction::In, &message);
+                        }
+                        if message_tx.send(message).await.is_err() {
+                            break;
                         }
-
                         ctx.request_repaint();
                     }
-                    Err(ref e) if e.kind() == tokio::io::ErrorKind::WouldBlock => {
-                        continue;
-                    }
                     Err(e) => {
-                        println!("{}", e.to_string());
-                        return;
+                        log::error!("Failed to receive from emulator: {}", e);
+                        break;
                     }
                 }
             }
+            // Close the session file with the connection.
+            recorder.lock().unwrap().take();
+            ctx.request_repaint();
         });
         (message_rx, handle)
     }
 
-    pub fn pop_messages(&mut self) -> Vec<String> {
+    pub fn pop_messages(&mut self) -> Vec<Vec<u8>> {
         let mut messages = Vec::new();
         loop {
             if let Ok(message) = self.message_rx.try_recv() {
@@ -188,11 +527,63 @@ impl Emulator {
         messages
     }
 
-    pub fn send_message<T: Into<String>>(&self, message: T) {
+    /// Waits for the next message. Returns `None` once the connection is
+    /// closed and every message has been received.
+    pub async fn recv_message(&mut self) -> Option<Vec<u8>> {
+        self.message_rx.recv().await
+    }
+
+    /// Sender for encoded `HostCommand`s, for sending from other threads.
+    pub fn message_sender(&self) -> Sender<String> {
+        self.message_tx.clone()
+    }
+
+    pub fn pop_sent_messages(&mut self) -> Vec<String> {
+        self.sent_messages.get_mut().unwrap().drain(..).collect()
+    }
+
+    /// Kills the spawned process. Does nothing when attached.
+    pub fn kill(&mut self) {
+        if let Some(process) = self.process.as_mut() {
+            if let Err(e) = process.start_kill() {
+                log::error!("Failed to kill emulator: {}", e);
+            }
+        }
+    }
+
+    /// Sends `cmd:stop` and waits up to `timeout` for the process to exit,
+    /// killing it afterwards. An attached emulator is only sent the command.
+    pub async fn shutdown(mut self, timeout: Duration) -> Option<ExitStatus> {
+        let _ = self.message_tx.send(HostCommand::Stop.encode()).await;
+        let process = self.process.as_mut()?;
+        match tokio::time::timeout(timeout, process.wait()).await {
+            Ok(status) => status.ok(),
+            Err(_) => {
+                log::warn!("Emulator did not stop within {:?}, killing it.", timeout);
+                process.kill().await.ok();
+                process.wait().await.ok()
+            }
+        }
+    }
+
+    pub fn send_message(&self, command: HostCommand) {
         let tx = self.message_tx.clone();
-        let _message = message.into();
+        let _message = command.encode();
+        if let Some(recorder) = self.recorder.lock().unwrap().as_mut() {
+            recorder.record(Direction::Out, _message.as_bytes());
+        }
+        let mut sent_messages = self.sent_messages.lock().unwrap();
+        if sent_messages.len() >= MAX_SENT_MESSAGES {
+            // Nobody is popping them, e.g. in a headless run.
+            sent_messages.pop_front();
+        }
+        sent_messages.push_back(_message.clone());
+        drop(sent_messages);
         tokio::spawn(async move {
-            tx.send(_message).await.unwrap();
+            // The connection is closed, or this is a finished replay.
+            if let Err(e) = tx.send(_message).await {
+                log::warn!("Emulator is not connected, dropped {:?}", e.0);
+            }
         });
     }
 }
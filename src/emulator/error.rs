@@ -0,0 +1,98 @@
+use std::{fmt, io, process::ExitStatus, time::Duration};
+
+#[derive(Debug)]
+pub enum EmulatorError {
+    /// The emulator executable could not be started.
+    Spawn(io::Error),
+    /// No free port could be reserved for the emulator.
+    Port(io::Error),
+    /// The emulator exited before the simulator could connect to it.
+    ProcessExited(ExitStatus),
+    /// The emulator did not accept a connection in time.
+    ConnectTimeout {
+        addr: String,
+        timeout: Duration,
+        source: io::Error,
+    },
+    /// The emulator did not answer the handshake in time.
+    HandshakeTimeout(Duration),
+    /// The emulator answered the handshake with something unexpected.
+    InvalidHandshake(String),
+    /// The emulator answered the handshake with another message, as
+    /// emulators from before the handshake do.
+    UnsupportedProtocol {
+        simulator: u32,
+        line: String,
+    },
+    IncompatibleVersion {
+        emulator: u32,
+        simulator: u32,
+    },
+    Io(io::Error),
+    /// A session file to replay could not be read.
+    InvalidSession(String),
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmulatorError::Spawn(e) => write!(f, "Failed to start emulator: {}", e),
+            EmulatorError::Port(e) => write!(f, "Failed to find a free port: {}", e),
+            EmulatorError::ProcessExited(status) => {
+                write!(f, "Emulator exited before connecting ({})", status)
+            }
+            EmulatorError::ConnectTimeout {
+                addr,
+                timeout,
+                source,
+            } => write!(
+                f,
+                "Could not connect to emulator at {} within {:.1}s: {}",
+                addr,
+                timeout.as_secs_f64(),
+                source
+            ),
+            EmulatorError::HandshakeTimeout(timeout) => write!(
+                f,
+                "Emulator did not answer the handshake within {:.1}s. It may be too old to support the protocol; update the emulator.",
+                timeout.as_secs_f64()
+            ),
+            EmulatorError::InvalidHandshake(reason) => {
+                write!(f, "Invalid handshake from emulator: {}", reason)
+            }
+            EmulatorError::UnsupportedProtocol { simulator, line } => write!(
+                f,
+                "Emulator does not support protocol v{} (sent {:?} instead of hello). Update the emulator.",
+                simulator, line
+            ),
+            EmulatorError::IncompatibleVersion {
+                emulator,
+                simulator,
+            } => write!(
+                f,
+                "Incompatible emulator protocol version: emulator v{}, simulator v{}. Update the emulator or the simulator.",
+                emulator, simulator
+            ),
+            EmulatorError::Io(e) => write!(f, "Emulator connection error: {}", e),
+            EmulatorError::InvalidSession(reason) => {
+                write!(f, "Failed to read session: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EmulatorError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EmulatorError::Spawn(e) | EmulatorError::Port(e) | EmulatorError::Io(e) => Some(e),
+            EmulatorError::ConnectTimeout { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for EmulatorError {
+    fn from(e: io::Error) -> Self {
+        EmulatorError::Io(e)
+    }
+}
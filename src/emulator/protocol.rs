@@ -0,0 +1,575 @@
+use std::fmt;
+
+/// Version of the line protocol spoken over the emulator socket.
+///
+/// Bumped whenever a change would be misread by the other side.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Optional feature of the protocol, announced by both sides in the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    IoPort,
+    Sync,
+    Stdin,
+    Pause,
+    Memory,
+    Speed,
+}
+
+impl Capability {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Capability::IoPort => "ioport",
+            Capability::Sync => "sync",
+            Capability::Stdin => "stdin",
+            Capability::Pause => "pause",
+            Capability::Memory => "memory",
+            Capability::Speed => "speed",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ioport" => Some(Capability::IoPort),
+            "sync" => Some(Capability::Sync),
+            "stdin" => Some(Capability::Stdin),
+            "pause" => Some(Capability::Pause),
+            "memory" => Some(Capability::Memory),
+            "speed" => Some(Capability::Speed),
+            _ => None,
+        }
+    }
+}
+
+pub fn encode_capabilities(capabilities: &[Capability]) -> String {
+    capabilities
+        .iter()
+        .map(|c| c.as_str())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Capabilities the other side does not know are dropped, so that newer
+/// peers can announce features without breaking the handshake.
+pub fn decode_capabilities(list: &str) -> Vec<Capability> {
+    list.split(',').filter_map(Capability::from_name).collect()
+}
+
+/// Escapes a line so that it contains no newline.
+///
+/// Backslashes are doubled and newlines become `\n`. This must match the
+/// emulator, which escapes every line it sends the same way.
+pub fn escape(line: &str) -> String {
+    let mut escaped = String::with_capacity(line.len());
+    for c in line.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Reverses `escape` in a single pass.
+///
+/// Unknown escape sequences and a trailing backslash are kept as they are.
+pub fn unescape(line: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(line.len());
+    let mut bytes = line.iter().copied();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            unescaped.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'n') => unescaped.push(b'\n'),
+            Some(b'\\') => unescaped.push(b'\\'),
+            Some(other) => unescaped.extend_from_slice(&[b'\\', other]),
+            None => unescaped.push(b'\\'),
+        }
+    }
+    unescaped
+}
+
+/// Message sent from the emulator to the simulator.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmulatorEvent {
+    Hello {
+        version: u32,
+        capabilities: Vec<Capability>,
+    },
+    /// Output of the program as it wrote it, which need not be UTF-8, e.g.
+    /// Shift_JIS.
+    Stdout(Vec<u8>),
+    IoPort {
+        port: u8,
+        value: u8,
+        state: usize,
+    },
+    Ready,
+    Sync {
+        state: usize,
+    },
+    /// Execution stopped after `cmd:pause` or `cmd:step` and waits for the
+    /// next command.
+    Paused {
+        state: usize,
+    },
+    /// The program exited with `code`.
+    Exit {
+        code: i32,
+        state: usize,
+    },
+    /// The emulator stopped the program because it cannot continue.
+    Crash {
+        state: usize,
+        reason: String,
+    },
+    Unknown(String),
+}
+
+/// Command sent from the simulator to the emulator.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostCommand {
+    Hello {
+        version: u32,
+        capabilities: Vec<Capability>,
+    },
+    Start,
+    Stop,
+    IoPort {
+        port: u8,
+        value: u8,
+    },
+    Stdin(String),
+    Pause,
+    Resume,
+    /// Runs the given number of states, then pauses again.
+    Step {
+        states: usize,
+    },
+    /// Limits emulation to the given multiple of real time. `None` runs as
+    /// fast as possible.
+    Speed(Option<f64>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    FieldCount {
+        line: String,
+        expected: usize,
+        found: usize,
+    },
+    InvalidField {
+        line: String,
+        field: &'static str,
+    },
+    UnknownCommand(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::FieldCount {
+                line,
+                expected,
+                found,
+            } => write!(
+                f,
+                "expected {} fields but found {}: {:?}",
+                expected, found, line
+            ),
+            DecodeError::InvalidField { line, field } => {
+                write!(f, "invalid {} field: {:?}", field, line)
+            }
+            DecodeError::UnknownCommand(line) => write!(f, "unknown command: {:?}", line),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+fn split_fields(line: &str, expected: usize) -> Result<Vec<&str>, DecodeError> {
+    let fields: Vec<&str> = line.split(':').collect();
+    if fields.len() != expected {
+        return Err(DecodeError::FieldCount {
+            line: line.to_string(),
+            expected,
+            found: fields.len(),
+        });
+    }
+    Ok(fields)
+}
+
+fn parse_hex(line: &str, field: &'static str, value: &str) -> Result<u8, DecodeError> {
+    u8::from_str_radix(value, 16).map_err(|_| DecodeError::InvalidField {
+        line: line.to_string(),
+        field,
+    })
+}
+
+fn parse_version(line: &str, value: &str) -> Result<u32, DecodeError> {
+    value.parse::<u32>().map_err(|_| DecodeError::InvalidField {
+        line: line.to_string(),
+        field: "version",
+    })
+}
+
+fn parse_state(line: &str, value: &str) -> Result<usize, DecodeError> {
+    value
+        .parse::<usize>()
+        .map_err(|_| DecodeError::InvalidField {
+            line: line.to_string(),
+            field: "state",
+        })
+}
+
+impl EmulatorEvent {
+    /// Emulator state the event happened at, if it carries one.
+    pub fn state(&self) -> Option<usize> {
+        match self {
+            EmulatorEvent::IoPort { state, .. }
+            | EmulatorEvent::Sync { state }
+            | EmulatorEvent::Paused { state }
+            | EmulatorEvent::Exit { state, .. }
+            | EmulatorEvent::Crash { state, .. } => Some(*state),
+            EmulatorEvent::Hello { .. }
+            | EmulatorEvent::Stdout(_)
+            | EmulatorEvent::Ready
+            | EmulatorEvent::Unknown(_) => None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let line = match self {
+            EmulatorEvent::Hello {
+                version,
+                capabilities,
+            } => format!("hello:{}:{}", version, encode_capabilities(capabilities)),
+            EmulatorEvent::Stdout(output) => return [b"stdout:", output.as_slice()].concat(),
+            EmulatorEvent::IoPort { port, value, state } => {
+                format!("ioport:{:x}:{:x}:{}", port, value, state)
+            }
+            EmulatorEvent::Ready => "ready".to_string(),
+            EmulatorEvent::Sync { state } => format!("sync:{}", state),
+            EmulatorEvent::Paused { state } => format!("paused:{}", state),
+            EmulatorEvent::Exit { code, state } => format!("exit:{}:{}", code, state),
+            EmulatorEvent::Crash { state, reason } => format!("crash:{}:{}", state, reason),
+            EmulatorEvent::Unknown(line) => line.clone(),
+        };
+        line.into_bytes()
+    }
+
+    /// Decodes an unescaped line. Only the output in `stdout` is kept as
+    /// bytes, the rest of the protocol is text.
+    pub fn decode(line: &[u8]) -> Result<Self, DecodeError> {
+        if let Some(output) = line.strip_prefix(b"stdout:") {
+            return Ok(EmulatorEvent::Stdout(output.to_vec()));
+        }
+        let line = String::from_utf8_lossy(line);
+        let line = line.as_ref();
+
+        match line.split(':').next().unwrap_or_default() {
+            "hello" => {
+                let fields = split_fields(line, 3)?;
+                Ok(EmulatorEvent::Hello {
+                    version: parse_version(line, fields[1])?,
+                    capabilities: decode_capabilities(fields[2]),
+                })
+            }
+            "ioport" => {
+                let fields = split_fields(line, 4)?;
+                Ok(EmulatorEvent::IoPort {
+                    port: parse_hex(line, "port", fields[1])?,
+                    value: parse_hex(line, "value", fields[2])?,
+                    state: parse_state(line, fields[3])?,
+                })
+            }
+            "ready" => {
+                split_fields(line, 1)?;
+                Ok(EmulatorEvent::Ready)
+            }
+            "sync" => {
+                let fields = split_fields(line, 2)?;
+                Ok(EmulatorEvent::Sync {
+                    state: parse_state(line, fields[1])?,
+                })
+            }
+            "paused" => {
+                let fields = split_fields(line, 2)?;
+                Ok(EmulatorEvent::Paused {
+                    state: parse_state(line, fields[1])?,
+                })
+            }
+            "exit" => {
+                let fields = split_fields(line, 3)?;
+                Ok(EmulatorEvent::Exit {
+                    code: fields[1].parse().map_err(|_| DecodeError::InvalidField {
+                        line: line.to_string(),
+                        field: "code",
+                    })?,
+                    state: parse_state(line, fields[2])?,
+                })
+            }
+            "crash" => {
+                // The reason is free text and may contain ':'.
+                let fields: Vec<&str> = line.splitn(3, ':').collect();
+                if fields.len() != 3 {
+                    return Err(DecodeError::FieldCount {
+                        line: line.to_string(),
+                        expected: 3,
+                        found: fields.len(),
+                    });
+                }
+                Ok(EmulatorEvent::Crash {
+                    state: parse_state(line, fields[1])?,
+                    reason: fields[2].to_string(),
+                })
+            }
+            _ => Ok(EmulatorEvent::Unknown(line.to_string())),
+        }
+    }
+}
+
+impl HostCommand {
+    pub fn encode(&self) -> String {
+        match self {
+            HostCommand::Hello {
+                version,
+                capabilities,
+            } => format!("hello:{}:{}", version, encode_capabilities(capabilities)),
+            HostCommand::Start => "cmd:start".to_string(),
+            HostCommand::Stop => "cmd:stop".to_string(),
+            HostCommand::Pause => "cmd:pause".to_string(),
+            HostCommand::Resume => "cmd:resume".to_string(),
+            HostCommand::Step { states } => format!("cmd:step:{}", states),
+            HostCommand::Speed(Some(speed)) => format!("cmd:speed:{}", speed),
+            HostCommand::Speed(None) => "cmd:speed:max".to_string(),
+            HostCommand::IoPort { port, value } => format!("ioport:{:x}:{:x}", port, value),
+            HostCommand::Stdin(text) => format!("stdin:{}", text),
+        }
+    }
+
+    pub fn decode(line: &str) -> Result<Self, DecodeError> {
+        if let Some(text) = line.strip_prefix("stdin:") {
+            return Ok(HostCommand::Stdin(text.to_string()));
+        }
+
+        match line.split(':').next().unwrap_or_default() {
+            "hello" => {
+                let fields = split_fields(line, 3)?;
+                Ok(HostCommand::Hello {
+                    version: parse_version(line, fields[1])?,
+                    capabilities: decode_capabilities(fields[2]),
+                })
+            }
+            "cmd" => {
+                let fields: Vec<&str> = line.split(':').collect();
+                match fields[1..] {
+                    ["start"] => Ok(HostCommand::Start),
+                    ["stop"] => Ok(HostCommand::Stop),
+                    ["pause"] => Ok(HostCommand::Pause),
+                    ["resume"] => Ok(HostCommand::Resume),
+                    ["step", states] => Ok(HostCommand::Step {
+                        states: parse_state(line, states)?,
+                    }),
+                    ["speed", "max"] => Ok(HostCommand::Speed(None)),
+                    ["speed", speed] => match speed.parse::<f64>() {
+                        Ok(speed) if speed > 0f64 => Ok(HostCommand::Speed(Some(speed))),
+                        _ => Err(DecodeError::InvalidField {
+                            line: line.to_string(),
+                            field: "speed",
+                        }),
+                    },
+                    _ => Err(DecodeError::UnknownCommand(line.to_string())),
+                }
+            }
+            "ioport" => {
+                let fields = split_fields(line, 3)?;
+                Ok(HostCommand::IoPort {
+                    port: parse_hex(line, "port", fields[1])?,
+                    value: parse_hex(line, "value", fields[2])?,
+                })
+            }
+            _ => Err(DecodeError::UnknownCommand(line.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_round_trip() {
+        let events = [
+            EmulatorEvent::Hello {
+                version: PROTOCOL_VERSION,
+                capabilities: vec![Capability::IoPort, Capability::Sync],
+            },
+            EmulatorEvent::Hello {
+                version: 2,
+                capabilities: Vec::new(),
+            },
+            EmulatorEvent::Stdout(b"hello: world".to_vec()),
+            EmulatorEvent::Stdout(Vec::new()),
+            EmulatorEvent::Stdout(b"\x82\xa0\n\xff".to_vec()),
+            EmulatorEvent::IoPort {
+                port: 0xb,
+                value: 0xf0,
+                state: 123456,
+            },
+            EmulatorEvent::Ready,
+            EmulatorEvent::Sync { state: 2_000_000 },
+            EmulatorEvent::Paused { state: 42 },
+            EmulatorEvent::Exit {
+                code: -1,
+                state: 1000,
+            },
+            EmulatorEvent::Crash {
+                state: 1000,
+                reason: "illegal instruction at 0x0100: 0xffff".to_string(),
+            },
+            EmulatorEvent::Unknown("foo:bar".to_string()),
+        ];
+        for event in events {
+            assert_eq!(EmulatorEvent::decode(&event.encode()), Ok(event));
+        }
+    }
+
+    #[test]
+    fn command_round_trip() {
+        let commands = [
+            HostCommand::Hello {
+                version: PROTOCOL_VERSION,
+                capabilities: vec![Capability::Stdin, Capability::Pause, Capability::Memory],
+            },
+            HostCommand::Start,
+            HostCommand::Stop,
+            HostCommand::IoPort {
+                port: 0x5,
+                value: 0x3,
+            },
+            HostCommand::Stdin("1:2".to_string()),
+            HostCommand::Pause,
+            HostCommand::Resume,
+            HostCommand::Step { states: 20_000 },
+            HostCommand::Speed(Some(0.01)),
+            HostCommand::Speed(None),
+        ];
+        for command in commands {
+            assert_eq!(HostCommand::decode(&command.encode()), Ok(command));
+        }
+    }
+
+    #[test]
+    fn decode_existing_wire_format() {
+        assert_eq!(
+            EmulatorEvent::decode(b"ioport:4:1f:200"),
+            Ok(EmulatorEvent::IoPort {
+                port: 0x4,
+                value: 0x1f,
+                state: 200
+            })
+        );
+        assert_eq!(
+            HostCommand::decode("ioport:5:3"),
+            Ok(HostCommand::IoPort { port: 5, value: 3 })
+        );
+    }
+
+    #[test]
+    fn escape_newlines_and_backslashes() {
+        assert_eq!(escape("a\\b\nc"), "a\\\\b\\nc");
+        assert_eq!(escape("no escape"), "no escape");
+    }
+
+    #[test]
+    fn escape_round_trip() {
+        let lines = [
+            "",
+            "plain",
+            "line\nbreak",
+            "C:\\new\\file",
+            // A literal backslash followed by 'n' must not become a newline.
+            "\\n",
+            "\\\n",
+            "\\\\n\n\\",
+            "日本語\n",
+        ];
+        for line in lines {
+            let escaped = escape(line);
+            assert!(!escaped.contains('\n'));
+            assert_eq!(unescape(escaped.as_bytes()), line.as_bytes());
+        }
+    }
+
+    #[test]
+    fn unescape_keeps_unknown_sequences() {
+        assert_eq!(unescape(b"a\\tb"), b"a\\tb");
+        assert_eq!(unescape(b"trailing\\"), b"trailing\\");
+    }
+
+    #[test]
+    fn decode_keeps_stdout_bytes() {
+        assert_eq!(
+            EmulatorEvent::decode(&unescape(b"stdout:a\xffb\\n\x82\xa0")),
+            Ok(EmulatorEvent::Stdout(b"a\xffb\n\x82\xa0".to_vec()))
+        );
+        assert_eq!(
+            EmulatorEvent::decode(b"ready\xff"),
+            Ok(EmulatorEvent::Unknown("ready\u{fffd}".to_string()))
+        );
+    }
+
+    #[test]
+    fn decode_hello_ignores_unknown_capabilities() {
+        assert_eq!(
+            EmulatorEvent::decode(b"hello:1:ioport,teleport,sync"),
+            Ok(EmulatorEvent::Hello {
+                version: 1,
+                capabilities: vec![Capability::IoPort, Capability::Sync],
+            })
+        );
+    }
+
+    #[test]
+    fn decode_malformed_lines() {
+        assert!(matches!(
+            EmulatorEvent::decode(b"ioport:4:1f"),
+            Err(DecodeError::FieldCount {
+                expected: 4,
+                found: 3,
+                ..
+            })
+        ));
+        assert!(matches!(
+            EmulatorEvent::decode(b"ioport:4:zz:0"),
+            Err(DecodeError::InvalidField { field: "value", .. })
+        ));
+        assert!(matches!(
+            EmulatorEvent::decode(b"sync:-1"),
+            Err(DecodeError::InvalidField { field: "state", .. })
+        ));
+        assert!(matches!(
+            EmulatorEvent::decode(b"ready:now"),
+            Err(DecodeError::FieldCount { .. })
+        ));
+        assert!(matches!(
+            EmulatorEvent::decode(b"hello:one:ioport"),
+            Err(DecodeError::InvalidField {
+                field: "version",
+                ..
+            })
+        ));
+        assert!(matches!(
+            HostCommand::decode("cmd:speed:0"),
+            Err(DecodeError::InvalidField { field: "speed", .. })
+        ));
+        assert!(matches!(
+            HostCommand::decode("cmd:jump"),
+            Err(DecodeError::UnknownCommand(_))
+        ));
+    }
+}
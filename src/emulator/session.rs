@@ -0,0 +1,177 @@
+//! Session files: every message exchanged with the emulator, as JSON lines.
+//!
+//! The first line is a `SessionHeader`, every following line a
+//! `SessionRecord`.
+
+use super::protocol::{self, Capability, EmulatorEvent};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    time::Instant,
+};
+
+pub const SESSION_FORMAT: &str = "h8pks-session";
+pub const SESSION_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Received from the emulator.
+    In,
+    /// Sent to the emulator.
+    Out,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionHeader {
+    pub format: String,
+    pub version: u32,
+    /// Negotiated capabilities, as in the handshake.
+    pub capabilities: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionRecord {
+    /// Wall time in seconds since the recording started.
+    pub time: f64,
+    pub dir: Direction,
+    /// Last emulator state known when the message was exchanged.
+    pub state: usize,
+    /// The unescaped message, with invalid UTF-8 replaced.
+    pub line: String,
+    /// The message as exchanged, if it is not valid UTF-8.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<Vec<u8>>,
+}
+
+impl SessionRecord {
+    /// The unescaped message as exchanged.
+    pub fn message(self) -> Vec<u8> {
+        self.bytes.unwrap_or_else(|| self.line.into_bytes())
+    }
+}
+
+pub struct SessionRecorder {
+    writer: BufWriter<File>,
+    start: Instant,
+    state: usize,
+}
+
+impl SessionRecorder {
+    pub fn create(path: &Path, capabilities: &[Capability]) -> io::Result<SessionRecorder> {
+        let mut recorder = SessionRecorder {
+            writer: BufWriter::new(File::create(path)?),
+            start: Instant::now(),
+            state: 0,
+        };
+        let header = SessionHeader {
+            format: SESSION_FORMAT.to_string(),
+            version: SESSION_VERSION,
+            capabilities: protocol::encode_capabilities(capabilities),
+        };
+        recorder.write_line(&header)?;
+        Ok(recorder)
+    }
+
+    pub fn record(&mut self, dir: Direction, message: &[u8]) {
+        if dir == Direction::In {
+            if let Some(state) = EmulatorEvent::decode(message).ok().and_then(|e| e.state()) {
+                self.state = state;
+            }
+        }
+        let (line, bytes) = match std::str::from_utf8(message) {
+            Ok(line) => (line.to_string(), None),
+            Err(_) => (
+                String::from_utf8_lossy(message).into_owned(),
+                Some(message.to_vec()),
+            ),
+        };
+        let record = SessionRecord {
+            time: self.start.elapsed().as_secs_f64(),
+            dir,
+            state: self.state,
+            line,
+            bytes,
+        };
+        if let Err(e) = self.write_line(&record) {
+            log::error!("Failed to record session: {}", e);
+        }
+    }
+
+    /// Flushes every line, so the file is complete whenever the session
+    /// ends, also if the simulator is killed.
+    fn write_line<T: Serialize>(&mut self, value: &T) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, value)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()
+    }
+}
+
+/// Reads a session file written by `SessionRecorder`.
+pub fn read_session(path: &Path) -> Result<(SessionHeader, Vec<SessionRecord>), String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut lines = BufReader::new(file).lines().enumerate();
+    let invalid = |number: usize, e: &dyn std::fmt::Display| {
+        format!("{}:{}: {}", path.display(), number + 1, e)
+    };
+
+    let header: SessionHeader = match lines.next() {
+        Some((number, line)) => {
+            let line = line.map_err(|e| invalid(number, &e))?;
+            serde_json::from_str(&line).map_err(|e| invalid(number, &e))?
+        }
+        None => return Err(format!("{}: empty session file", path.display())),
+    };
+    if header.format != SESSION_FORMAT || header.version != SESSION_VERSION {
+        return Err(format!(
+            "{}: unsupported session format {} v{}",
+            path.display(),
+            header.format,
+            header.version
+        ));
+    }
+
+    let mut records = Vec::new();
+    for (number, line) in lines {
+        let line = line.map_err(|e| invalid(number, &e))?;
+        if line.is_empty() {
+            continue;
+        }
+        records.push(serde_json::from_str(&line).map_err(|e| invalid(number, &e))?);
+    }
+    Ok((header, records))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_recorded_session() {
+        let path = std::env::temp_dir().join(format!("h8pks-session-{}.jsonl", std::process::id()));
+        let mut recorder = SessionRecorder::create(&path, &[Capability::IoPort]).unwrap();
+        recorder.record(Direction::In, b"ready");
+        recorder.record(Direction::Out, b"cmd:start");
+        recorder.record(Direction::In, b"ioport:b:f0:200");
+        recorder.record(Direction::In, b"stdout:a\nb\x82\xa0");
+
+        let (header, records) = read_session(&path).unwrap();
+        assert_eq!(header.capabilities, "ioport");
+        let records: Vec<_> = records
+            .into_iter()
+            .map(|r| (r.dir, r.state, r.message()))
+            .collect();
+        assert_eq!(
+            records,
+            vec![
+                (Direction::In, 0, b"ready".to_vec()),
+                (Direction::Out, 0, b"cmd:start".to_vec()),
+                (Direction::In, 200, b"ioport:b:f0:200".to_vec()),
+                (Direction::In, 200, b"stdout:a\nb\x82\xa0".to_vec()),
+            ]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
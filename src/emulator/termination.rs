@@ -0,0 +1,43 @@
+use super::CLOCK_FREQUENCY;
+use std::{fmt, process::ExitStatus};
+
+/// Why a run of the emulated program ended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TerminationReason {
+    /// The program returned from `main` or called `exit`.
+    Exited(i32),
+    StoppedByUser,
+    /// The emulator stopped the program, e.g. on an illegal instruction.
+    Crashed(String),
+    /// The connection was lost without the emulator reporting why.
+    EmulatorDied,
+    /// The simulator detached and left the emulator running.
+    Detached,
+}
+
+impl fmt::Display for TerminationReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TerminationReason::Exited(code) => write!(f, "Program exited with code {}", code),
+            TerminationReason::StoppedByUser => write!(f, "Stopped by user"),
+            TerminationReason::Crashed(reason) => write!(f, "Program crashed: {}", reason),
+            TerminationReason::EmulatorDied => write!(f, "Emulator died"),
+            TerminationReason::Detached => write!(f, "Detached from emulator"),
+        }
+    }
+}
+
+/// Summary of a finished run.
+#[derive(Debug, Clone)]
+pub struct RunSummary {
+    pub reason: TerminationReason,
+    /// `None` when attached or when the process status could not be read.
+    pub process_status: Option<ExitStatus>,
+    pub state: usize,
+}
+
+impl RunSummary {
+    pub fn elapsed_secs(&self) -> f64 {
+        self.state as f64 / CLOCK_FREQUENCY as f64
+    }
+}
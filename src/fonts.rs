@@ -0,0 +1,69 @@
+//! Fonts with Japanese glyphs, which the default fonts of egui lack.
+//!
+//! A font of the system is loaded, or the one at `CJK_FONT_ENV` if set.
+
+use eframe::egui::{self, FontData, FontDefinitions, FontFamily};
+use std::{path::PathBuf, sync::Arc};
+
+/// Environment variable with the path of a font to use instead.
+pub const CJK_FONT_ENV: &str = "H8PKS_CJK_FONT";
+
+const CJK_FONT_NAME: &str = "cjk";
+
+/// Fonts with Japanese glyphs found on common systems, most preferred first.
+const SYSTEM_CJK_FONTS: &[&str] = &[
+    // Windows
+    "C:\\Windows\\Fonts\\BIZ-UDGothicR.ttc",
+    "C:\\Windows\\Fonts\\msgothic.ttc",
+    "C:\\Windows\\Fonts\\YuGothR.ttc",
+    "C:\\Windows\\Fonts\\meiryo.ttc",
+    // macOS
+    "/System/Library/Fonts/ヒラギノ角ゴシック W3.ttc",
+    "/System/Library/Fonts/ヒラギノ角ゴ ProN W3.otf",
+    "/Library/Fonts/Osaka.ttf",
+    // Linux
+    "/usr/share/fonts/opentype/noto/NotoSansCJK-Regular.ttc",
+    "/usr/share/fonts/noto-cjk/NotoSansCJK-Regular.ttc",
+    "/usr/share/fonts/google-noto-cjk/NotoSansCJK-Regular.ttc",
+    "/usr/share/fonts/truetype/fonts-japanese-gothic.ttf",
+    "/usr/share/fonts/opentype/ipafont-gothic/ipag.ttf",
+    "/usr/share/fonts/truetype/takao-gothic/TakaoGothic.ttf",
+];
+
+fn load_cjk_font() -> Option<FontData> {
+    if let Some(path) = std::env::var_os(CJK_FONT_ENV) {
+        match std::fs::read(&path) {
+            Ok(font) => return Some(FontData::from_owned(font)),
+            Err(e) => log::warn!("Failed to load {}: {}", PathBuf::from(path).display(), e),
+        }
+    }
+
+    SYSTEM_CJK_FONTS.iter().find_map(|path| {
+        let font = std::fs::read(path).ok()?;
+        log::info!("Using font {}", path);
+        Some(FontData::from_owned(font))
+    })
+}
+
+/// Adds a CJK font as fallback of both font families.
+pub fn install_fonts(ctx: &egui::Context) {
+    let Some(font) = load_cjk_font() else {
+        log::warn!(
+            "No font with Japanese glyphs found, set {} to the path of one.",
+            CJK_FONT_ENV
+        );
+        return;
+    };
+    let mut fonts = FontDefinitions::default();
+    fonts
+        .font_data
+        .insert(CJK_FONT_NAME.to_string(), Arc::new(font));
+    for family in [FontFamily::Proportional, FontFamily::Monospace] {
+        fonts
+            .families
+            .entry(family)
+            .or_default()
+            .push(CJK_FONT_NAME.to_string());
+    }
+    ctx.set_fonts(fonts);
+}
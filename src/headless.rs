@@ -0,0 +1,263 @@
+//! `h8pks run`: executes a program without the GUI, e.g. for CI and grading.
+
+use crate::board;
+use crate::emulator::{
+    get_emulator_path,
+    protocol::{Capability, EmulatorEvent, HostCommand},
+    Emulator, EmulatorError, LogLine, LogStream, TerminationReason, STOP_TIMEOUT,
+};
+use eframe::egui;
+use std::{
+    io::Write,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+/// Exit code when the timeout elapses, as with coreutils `timeout`.
+pub const EXIT_TIMEOUT: i32 = 124;
+/// Exit code when the emulator fails to start or dies.
+pub const EXIT_EMULATOR_ERROR: i32 = 125;
+/// Exit code when the emulated program crashes.
+pub const EXIT_CRASHED: i32 = 126;
+/// Exit code for invalid command line arguments.
+pub const EXIT_USAGE: i32 = 2;
+
+pub const USAGE: &str = "usage: h8pks run --elf <prog.elf> [--args <args>] [--timeout <duration>]";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunOptions {
+    pub elf_path: String,
+    pub elf_args: String,
+    /// `None` runs until the program exits.
+    pub timeout: Option<Duration>,
+}
+
+impl RunOptions {
+    /// Parses the arguments following `run`.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<RunOptions, String> {
+        let mut elf_path = None;
+        let mut elf_args = String::new();
+        let mut timeout = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+            match arg.as_str() {
+                "--elf" => elf_path = Some(value("--elf")?),
+                "--args" => elf_args = value("--args")?,
+                "--timeout" => timeout = Some(parse_duration(&value("--timeout")?)?),
+                _ => return Err(format!("unknown argument: {}", arg)),
+            }
+        }
+        Ok(RunOptions {
+            elf_path: elf_path.ok_or("--elf is required")?,
+            elf_args,
+            timeout,
+        })
+    }
+}
+
+/// Parses durations like `10s`, `500ms`, `2m` or plain seconds.
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid duration: {}", text);
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(i) => text.split_at(i),
+        None => (text, "s"),
+    };
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    let secs = match unit {
+        "ms" => number / 1000.0,
+        "s" => number,
+        "m" => number * 60.0,
+        _ => return Err(invalid()),
+    };
+    Duration::try_from_secs_f64(secs).map_err(|_| invalid())
+}
+
+/// Runs the program with the installed emulator and returns the exit code
+/// for this process.
+pub async fn run(options: &RunOptions, out: &mut impl Write) -> i32 {
+    match get_emulator_path() {
+        Ok(path) => run_with_path(path, options, out).await,
+        Err(e) => {
+            log::error!("{}", e);
+            EXIT_EMULATOR_ERROR
+        }
+    }
+}
+
+/// Like `run`, with the emulator at `emulator_path`.
+///
+/// Program stdout is written to `out`, output of the emulator process itself
+/// goes to stderr, and stdin of this process is forwarded to the program.
+pub async fn run_with_path(
+    emulator_path: PathBuf,
+    options: &RunOptions,
+    out: &mut impl Write,
+) -> i32 {
+    let mut emulator = match spawn_emulator(emulator_path, options).await {
+        Ok(emulator) => emulator,
+        Err(e) => {
+            log::error!("{}", e);
+            return EXIT_EMULATOR_ERROR;
+        }
+    };
+
+    let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+    let mut termination = None;
+    loop {
+        let Ok(message) = recv_until(&mut emulator, deadline).await else {
+            log::error!("Timed out after {:?}", options.timeout.unwrap());
+            emulator.shutdown(STOP_TIMEOUT).await;
+            return EXIT_TIMEOUT;
+        };
+        let Some(message) = message else {
+            break;
+        };
+        match EmulatorEvent::decode(&message) {
+            Ok(EmulatorEvent::Stdout(output)) => {
+                let _ = out.write_all(&output).and_then(|_| out.flush());
+            }
+            Ok(EmulatorEvent::Ready) => {
+                if emulator.supports(Capability::IoPort) {
+                    emulator.send_message(HostCommand::IoPort {
+                        port: board::SWITCH_PORT,
+                        value: board::SWITCHES_RELEASED,
+                    });
+                }
+                if emulator.supports(Capability::Stdin) {
+                    spawn_stdin_forwarder(&emulator);
+                }
+                emulator.send_message(HostCommand::Start);
+            }
+            Ok(EmulatorEvent::Exit { code, .. }) => {
+                termination = Some(TerminationReason::Exited(code));
+                break;
+            }
+            Ok(EmulatorEvent::Crash { reason, .. }) => {
+                termination = Some(TerminationReason::Crashed(reason));
+                break;
+            }
+            Ok(_) => (),
+            Err(e) => log::warn!("Failed to decode emulator message: {}", e),
+        }
+    }
+
+    if let Some(status) = emulator.shutdown(STOP_TIMEOUT).await {
+        log::info!("Emulator process: {}", status);
+    }
+    let reason = termination.unwrap_or(TerminationReason::EmulatorDied);
+    match reason {
+        TerminationReason::Exited(code) => {
+            log::info!("{}", reason);
+            code
+        }
+        TerminationReason::Crashed(_) => {
+            log::error!("{}", reason);
+            EXIT_CRASHED
+        }
+        _ => {
+            log::error!("{}", reason);
+            EXIT_EMULATOR_ERROR
+        }
+    }
+}
+
+/// Executes the elf of `options`, with output of the emulator process itself
+/// going to stderr.
+pub(crate) async fn spawn_emulator(
+    emulator_path: PathBuf,
+    options: &RunOptions,
+) -> Result<Emulator, EmulatorError> {
+    let (log_tx, mut log_rx) = tokio::sync::mpsc::channel::<LogLine>(64);
+    tokio::spawn(async move {
+        while let Some(line) = log_rx.recv().await {
+            match line.stream {
+                LogStream::Stdout => eprintln!("[emulator] {}", line.text),
+                LogStream::Stderr => eprintln!("[emulator:err] {}", line.text),
+            }
+        }
+    });
+
+    Emulator::execute_with_path(
+        emulator_path,
+        options.elf_path.clone(),
+        options.elf_args.clone(),
+        None,
+        None,
+        log_tx,
+        egui::Context::default(),
+    )
+    .await
+}
+
+/// Waits for the next message, or returns `Err` once `deadline` has passed.
+pub(crate) async fn recv_until(
+    emulator: &mut Emulator,
+    deadline: Option<Instant>,
+) -> Result<Option<Vec<u8>>, tokio::time::error::Elapsed> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), emulator.recv_message()).await,
+        None => Ok(emulator.recv_message().await),
+    }
+}
+
+/// Reads stdin on a plain thread, as a blocking read would keep the tokio
+/// runtime from shutting down.
+fn spawn_stdin_forwarder(emulator: &Emulator) {
+    let tx = emulator.message_sender();
+    std::thread::spawn(move || {
+        let mut line = String::new();
+        while let Ok(n) = std::io::stdin().read_line(&mut line) {
+            if n == 0
+                || tx
+                    .blocking_send(HostCommand::Stdin(line.clone()).encode())
+                    .is_err()
+            {
+                break;
+            }
+            line.clear();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_run_options() {
+        assert_eq!(
+            RunOptions::parse(args(&[
+                "--elf",
+                "a.elf",
+                "--args",
+                "1 2",
+                "--timeout",
+                "10s"
+            ])),
+            Ok(RunOptions {
+                elf_path: "a.elf".to_string(),
+                elf_args: "1 2".to_string(),
+                timeout: Some(Duration::from_secs(10)),
+            })
+        );
+        assert!(RunOptions::parse(args(&["--args", "1"])).is_err());
+        assert!(RunOptions::parse(args(&["--elf"])).is_err());
+        assert!(RunOptions::parse(args(&["--elf", "a.elf", "--verbose"])).is_err());
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("10"), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_duration("1.5s"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert!(parse_duration("10h").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("-1s").is_err());
+    }
+}
@@ -0,0 +1,9 @@
+pub mod board;
+pub mod emulator;
+pub mod fonts;
+pub mod headless;
+pub mod scenario;
+pub mod simulator;
+pub mod update;
+pub mod utils;
+pub mod vcd;
@@ -1,17 +1,34 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")] // hide console window on Windows in release
 
-use crate::simulator::Simulator;
 use eframe::egui;
+use h8pks::{
+    emulator, fonts,
+    headless::{self, RunOptions},
+    scenario::{self, TestOptions},
+    simulator::Simulator,
+    update::Updater,
+};
 use std::env::{self};
-use update::Updater;
-
-mod emulator;
-mod simulator;
-mod update;
-mod utils;
 
 #[tokio::main]
 async fn main() -> Result<(), eframe::Error> {
+    let mut args = env::args().skip(1);
+    match args.next().as_deref() {
+        Some("run") => {
+            attach_console();
+            env::set_var("RUST_LOG", "warn");
+            env_logger::init();
+            std::process::exit(run_headless(args).await);
+        }
+        Some("test") => {
+            attach_console();
+            env::set_var("RUST_LOG", "warn");
+            env_logger::init();
+            std::process::exit(run_scenario(args).await);
+        }
+        _ => (),
+    }
+
     env::set_var("RUST_LOG", "info");
     env_logger::init(); // Log to stderr (if you run with `RUST_LOG=debug`).
 
@@ -24,7 +41,8 @@ async fn main() -> Result<(), eframe::Error> {
     eframe::run_native(
         "H8 Practice Kit Simulator",
         options,
-        Box::new(|_cc| {
+        Box::new(|cc| {
+            fonts::install_fonts(&cc.egui_ctx);
             let app = Box::<MyApp>::default();
             if let Some(emulator_version) = &app.emulator_version {
                 log::info!("Emulator version: {}", emulator_version);
@@ -37,6 +55,43 @@ async fn main() -> Result<(), eframe::Error> {
     )
 }
 
+/// Release builds use the windows subsystem and start without a console, so
+/// the command line subcommands borrow the console of the calling shell.
+#[cfg(windows)]
+fn attach_console() {
+    use windows_sys::Win32::System::Console::{AttachConsole, ATTACH_PARENT_PROCESS};
+    // Fails if there is no parent console or one is already attached; either way
+    // there is nothing more to do.
+    unsafe {
+        AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
+
+#[cfg(not(windows))]
+fn attach_console() {}
+
+async fn run_headless(args: impl Iterator<Item = String>) -> i32 {
+    let options = match RunOptions::parse(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, headless::USAGE);
+            return headless::EXIT_USAGE;
+        }
+    };
+    headless::run(&options, &mut std::io::stdout()).await
+}
+
+async fn run_scenario(args: impl Iterator<Item = String>) -> i32 {
+    let options = match TestOptions::parse(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, scenario::USAGE);
+            return headless::EXIT_USAGE;
+        }
+    };
+    scenario::run(&options, &mut std::io::stdout()).await
+}
+
 struct MyApp {
     emulator_version: Option<String>,
     updater: Updater,
@@ -61,4 +116,8 @@ impl eframe::App for MyApp {
             self.updater.update(ui, ctx);
         });
     }
+
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        self.simulator.shutdown();
+    }
 }
@@ -0,0 +1,594 @@
+//! Scenario files for automated board tests: switch stimuli at given
+//! emulated times and expectations on the LEDs, 7-seg digits and stdout.
+//!
+//! ```toml
+//! elf = "prog.elf"   # relative to the scenario file
+//! args = ""
+//! timeout = "30s"    # wall clock
+//!
+//! [[stimulus]]
+//! at = "0.5s"
+//! toggle_switch = 1
+//! on = true
+//!
+//! [[stimulus]]
+//! at = "1s"
+//! push_switch = 0
+//! duration = "100ms" # default
+//!
+//! [[expect]]
+//! at = "1s"
+//! port_b = 0xF0
+//!
+//! [[expect]]
+//! at = "1.5s"
+//! seven_seg = "12 4" # ' ' is an unlit digit
+//!
+//! [[expect]]
+//! stdout = "hello"
+//! at = "2s"          # optional deadline
+//! ```
+
+use crate::{
+    board,
+    emulator::{protocol::Capability, protocol::EmulatorEvent, CLOCK_FREQUENCY, STOP_TIMEOUT},
+    headless::{self, parse_duration, RunOptions},
+};
+use std::{
+    fmt,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use toml_edit::{DocumentMut, Item, Table};
+
+mod runner;
+
+pub use runner::ScenarioRunner;
+
+/// Used when neither the scenario nor the command line sets a timeout.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_PUSH_DURATION: Duration = Duration::from_millis(100);
+
+pub const USAGE: &str =
+    "usage: h8pks test <scenario.toml> [--elf <prog.elf>] [--args <args>] [--timeout <duration>]";
+
+#[derive(Debug)]
+pub struct ScenarioError(pub String);
+
+impl fmt::Display for ScenarioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ScenarioError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Toggle {
+        switch: usize,
+        on: bool,
+    },
+    /// Pressed at `at` and released `duration` states later.
+    Push {
+        switch: usize,
+        duration: usize,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stimulus {
+    /// Emulated time in states.
+    pub at: usize,
+    pub action: Action,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Check {
+    PortB(u8),
+    SevenSeg([Option<u8>; 4]),
+    Stdout(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expectation {
+    /// Emulated time in states. For `Stdout` it is a deadline and optional.
+    pub at: Option<usize>,
+    pub check: Check,
+}
+
+fn to_states(duration: Duration) -> usize {
+    (duration.as_secs_f64() * CLOCK_FREQUENCY as f64).round() as usize
+}
+
+fn format_time(states: usize) -> String {
+    format!("{}s", states as f64 / CLOCK_FREQUENCY as f64)
+}
+
+fn format_digits(digits: &[Option<u8>; 4]) -> String {
+    digits
+        .iter()
+        .map(|digit| match digit {
+            Some(d) => char::from_digit(*d as u32, 16).unwrap_or('?'),
+            None => ' ',
+        })
+        .collect()
+}
+
+impl fmt::Display for Expectation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.check {
+            Check::PortB(value) => write!(f, "PortB == 0x{:02x}", value)?,
+            Check::SevenSeg(digits) => write!(f, "7seg == {:?}", format_digits(digits))?,
+            Check::Stdout(text) => write!(f, "stdout contains {:?}", text)?,
+        }
+        match (self.at, &self.check) {
+            (Some(at), Check::Stdout(_)) => write!(f, " by {}", format_time(at)),
+            (Some(at), _) => write!(f, " at {}", format_time(at)),
+            (None, _) => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scenario {
+    pub elf: Option<String>,
+    pub args: String,
+    /// Wall clock time until the run is aborted.
+    pub timeout: Option<Duration>,
+    pub stimuli: Vec<Stimulus>,
+    pub expectations: Vec<Expectation>,
+}
+
+/// Reads the fields of one table, rejecting unknown keys.
+struct Fields<'a> {
+    table: &'a Table,
+    context: String,
+}
+
+impl<'a> Fields<'a> {
+    fn new(table: &'a Table, context: String, known: &[&str]) -> Result<Self, ScenarioError> {
+        if let Some((key, _)) = table.iter().find(|(key, _)| !known.contains(key)) {
+            return Err(ScenarioError(format!("{}: unknown key {:?}", context, key)));
+        }
+        Ok(Fields { table, context })
+    }
+
+    fn error(&self, key: &str, expected: &str) -> ScenarioError {
+        ScenarioError(format!("{}: {} must be {}", self.context, key, expected))
+    }
+
+    fn str(&self, key: &str) -> Result<Option<&'a str>, ScenarioError> {
+        match self.table.get(key) {
+            None => Ok(None),
+            Some(item) => item
+                .as_str()
+                .map(Some)
+                .ok_or_else(|| self.error(key, "a string")),
+        }
+    }
+
+    fn bool(&self, key: &str) -> Result<Option<bool>, ScenarioError> {
+        match self.table.get(key) {
+            None => Ok(None),
+            Some(item) => item
+                .as_bool()
+                .map(Some)
+                .ok_or_else(|| self.error(key, "true or false")),
+        }
+    }
+
+    fn int(&self, key: &str, max: i64) -> Result<Option<i64>, ScenarioError> {
+        match self.table.get(key) {
+            None => Ok(None),
+            Some(item) => item
+                .as_integer()
+                .filter(|n| (0..=max).contains(n))
+                .map(Some)
+                .ok_or_else(|| self.error(key, &format!("an integer from 0 to {}", max))),
+        }
+    }
+
+    fn duration(&self, key: &str) -> Result<Option<Duration>, ScenarioError> {
+        match self.str(key)? {
+            None => Ok(None),
+            Some(text) => parse_duration(text)
+                .map(Some)
+                .map_err(|e| ScenarioError(format!("{}: {}: {}", self.context, key, e))),
+        }
+    }
+
+    /// Emulated time in states.
+    fn time(&self, key: &str) -> Result<Option<usize>, ScenarioError> {
+        Ok(self.duration(key)?.map(to_states))
+    }
+
+    fn required<T>(&self, key: &str, value: Option<T>) -> Result<T, ScenarioError> {
+        value.ok_or_else(|| ScenarioError(format!("{}: {} is required", self.context, key)))
+    }
+}
+
+fn tables<'a>(doc: &'a DocumentMut, key: &str) -> Result<Vec<&'a Table>, ScenarioError> {
+    match doc.get(key) {
+        None => Ok(Vec::new()),
+        Some(Item::ArrayOfTables(array)) => Ok(array.iter().collect()),
+        Some(_) => Err(ScenarioError(format!(
+            "{} must be an array of tables ([[{}]])",
+            key, key
+        ))),
+    }
+}
+
+fn parse_stimulus(table: &Table, index: usize) -> Result<Stimulus, ScenarioError> {
+    let fields = Fields::new(
+        table,
+        format!("stimulus {}", index + 1),
+        &["at", "toggle_switch", "on", "push_switch", "duration"],
+    )?;
+    let max_switch = board::SWITCH_COUNT as i64 - 1;
+    let action = match (
+        fields.int("toggle_switch", max_switch)?,
+        fields.int("push_switch", max_switch)?,
+    ) {
+        (Some(switch), None) => Action::Toggle {
+            switch: switch as usize,
+            on: fields.required("on", fields.bool("on")?)?,
+        },
+        (None, Some(switch)) => Action::Push {
+            switch: switch as usize,
+            duration: fields
+                .time("duration")?
+                .unwrap_or(to_states(DEFAULT_PUSH_DURATION)),
+        },
+        _ => {
+            return Err(ScenarioError(format!(
+                "{}: needs either toggle_switch or push_switch",
+                fields.context
+            )))
+        }
+    };
+    Ok(Stimulus {
+        at: fields.required("at", fields.time("at")?)?,
+        action,
+    })
+}
+
+fn parse_digits(fields: &Fields, text: &str) -> Result<[Option<u8>; 4], ScenarioError> {
+    let chars: Vec<char> = text.chars().collect();
+    if chars.len() != 4 {
+        return Err(fields.error("seven_seg", "4 characters"));
+    }
+    let mut digits = [None; 4];
+    for (digit, c) in digits.iter_mut().zip(chars) {
+        *digit = match c {
+            ' ' => None,
+            _ => Some(
+                c.to_digit(16)
+                    .ok_or_else(|| fields.error("seven_seg", "hex digits or spaces"))?
+                    as u8,
+            ),
+        };
+    }
+    Ok(digits)
+}
+
+fn parse_expectation(table: &Table, index: usize) -> Result<Expectation, ScenarioError> {
+    let fields = Fields::new(
+        table,
+        format!("expect {}", index + 1),
+        &["at", "port_b", "seven_seg", "stdout"],
+    )?;
+    let at = fields.time("at")?;
+    let check = match (
+        fields.int("port_b", u8::MAX as i64)?,
+        fields.str("seven_seg")?,
+        fields.str("stdout")?,
+    ) {
+        (Some(value), None, None) => Check::PortB(value as u8),
+        (None, Some(digits), None) => Check::SevenSeg(parse_digits(&fields, digits)?),
+        (None, None, Some(text)) => Check::Stdout(text.to_string()),
+        _ => {
+            return Err(ScenarioError(format!(
+                "{}: needs exactly one of port_b, seven_seg or stdout",
+                fields.context
+            )))
+        }
+    };
+    if !matches!(check, Check::Stdout(_)) {
+        fields.required("at", at)?;
+    }
+    Ok(Expectation { at, check })
+}
+
+impl Scenario {
+    pub fn parse(text: &str) -> Result<Scenario, ScenarioError> {
+        let doc: DocumentMut = text
+            .parse()
+            .map_err(|e: toml_edit::TomlError| ScenarioError(e.to_string()))?;
+        let fields = Fields::new(
+            doc.as_table(),
+            "scenario".to_string(),
+            &["elf", "args", "timeout", "stimulus", "expect"],
+        )?;
+        Ok(Scenario {
+            elf: fields.str("elf")?.map(str::to_string),
+            args: fields.str("args")?.unwrap_or_default().to_string(),
+            timeout: fields.duration("timeout")?,
+            stimuli: tables(&doc, "stimulus")?
+                .into_iter()
+                .enumerate()
+                .map(|(i, table)| parse_stimulus(table, i))
+                .collect::<Result<_, _>>()?,
+            expectations: tables(&doc, "expect")?
+                .into_iter()
+                .enumerate()
+                .map(|(i, table)| parse_expectation(table, i))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Reads a scenario file. `elf` is made relative to the file's directory.
+    pub fn load(path: &Path) -> Result<Scenario, ScenarioError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| ScenarioError(format!("{}: {}", path.display(), e)))?;
+        let mut scenario = Scenario::parse(&text)
+            .map_err(|e| ScenarioError(format!("{}: {}", path.display(), e)))?;
+        if let (Some(elf), Some(dir)) = (&scenario.elf, path.parent()) {
+            scenario.elf = Some(dir.join(elf).to_string_lossy().into_owned());
+        }
+        Ok(scenario)
+    }
+}
+
+/// Command line of `h8pks test`. The options override the scenario file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestOptions {
+    pub scenario_path: PathBuf,
+    pub elf_path: Option<String>,
+    pub elf_args: Option<String>,
+    pub timeout: Option<Duration>,
+}
+
+impl TestOptions {
+    /// Parses the arguments following `test`.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<TestOptions, String> {
+        let mut scenario_path = None;
+        let mut elf_path = None;
+        let mut elf_args = None;
+        let mut timeout = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+            match arg.as_str() {
+                "--elf" => elf_path = Some(value("--elf")?),
+                "--args" => elf_args = Some(value("--args")?),
+                "--timeout" => timeout = Some(parse_duration(&value("--timeout")?)?),
+                _ if arg.starts_with("--") => return Err(format!("unknown argument: {}", arg)),
+                _ if scenario_path.is_none() => scenario_path = Some(PathBuf::from(arg)),
+                _ => return Err(format!("unexpected argument: {}", arg)),
+            }
+        }
+        Ok(TestOptions {
+            scenario_path: scenario_path.ok_or("a scenario file is required")?,
+            elf_path,
+            elf_args,
+            timeout,
+        })
+    }
+
+    /// Combines the options with `scenario` into the options of the run.
+    pub fn run_options(&self, scenario: &Scenario) -> Result<RunOptions, ScenarioError> {
+        Ok(RunOptions {
+            elf_path: self
+                .elf_path
+                .clone()
+                .or_else(|| scenario.elf.clone())
+                .ok_or_else(|| {
+                    ScenarioError("no elf given in the scenario or with --elf".into())
+                })?,
+            elf_args: self
+                .elf_args
+                .clone()
+                .unwrap_or_else(|| scenario.args.clone()),
+            timeout: Some(self.timeout.or(scenario.timeout).unwrap_or(DEFAULT_TIMEOUT)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckResult {
+    pub expectation: Expectation,
+    /// `Err` holds why the expectation failed.
+    pub outcome: Result<(), String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Report {
+    pub results: Vec<CheckResult>,
+    /// Set when the run itself failed, e.g. on a timeout.
+    pub error: Option<String>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.error.is_none() && self.results.iter().all(|result| result.outcome.is_ok())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for result in &self.results {
+            match &result.outcome {
+                Ok(()) => writeln!(f, "PASS  {}", result.expectation)?,
+                Err(reason) => writeln!(f, "FAIL  {}: {}", result.expectation, reason)?,
+            }
+        }
+        if let Some(error) = &self.error {
+            writeln!(f, "ERROR {}", error)?;
+        }
+        let failed = self.results.iter().filter(|r| r.outcome.is_err()).count();
+        write!(
+            f,
+            "{} passed, {} failed",
+            self.results.len() - failed,
+            failed
+        )
+    }
+}
+
+/// Runs `scenario` with the emulator at `emulator_path`.
+pub async fn run_with_path(
+    emulator_path: PathBuf,
+    scenario: &Scenario,
+    options: &RunOptions,
+) -> Report {
+    let mut emulator = match headless::spawn_emulator(emulator_path, options).await {
+        Ok(emulator) => emulator,
+        Err(e) => {
+            return ScenarioRunner::new(scenario, false).finish(Some(e.to_string()));
+        }
+    };
+    let mut runner = ScenarioRunner::new(scenario, emulator.supports(Capability::Pause));
+
+    let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+    let mut error = None;
+    while !runner.is_done() {
+        let Ok(message) = headless::recv_until(&mut emulator, deadline).await else {
+            error = Some(format!("timed out after {:?}", options.timeout.unwrap()));
+            break;
+        };
+        let Some(message) = message else {
+            break;
+        };
+        match EmulatorEvent::decode(&message) {
+            Ok(event) => {
+                for command in runner.handle_event(&event) {
+                    emulator.send_message(command);
+                }
+            }
+            Err(e) => log::warn!("Failed to decode emulator message: {}", e),
+        }
+    }
+    emulator.shutdown(STOP_TIMEOUT).await;
+    runner.finish(error)
+}
+
+/// Runs `h8pks test` and returns the exit code for this process.
+pub async fn run(options: &TestOptions, out: &mut impl Write) -> i32 {
+    let result = Scenario::load(&options.scenario_path)
+        .and_then(|scenario| Ok((options.run_options(&scenario)?, scenario)));
+    let (run_options, scenario) = match result {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("{}", e);
+            return headless::EXIT_USAGE;
+        }
+    };
+    let emulator_path = match crate::emulator::get_emulator_path() {
+        Ok(path) => path,
+        Err(e) => {
+            log::error!("{}", e);
+            return headless::EXIT_EMULATOR_ERROR;
+        }
+    };
+    let report = run_with_path(emulator_path, &scenario, &run_options).await;
+    let _ = writeln!(out, "{}", report);
+    if report.passed() {
+        0
+    } else {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_scenario() {
+        let scenario = Scenario::parse(
+            r#"
+            elf = "prog.elf"
+            timeout = "5s"
+
+            [[stimulus]]
+            at = "0.5s"
+            toggle_switch = 1
+            on = true
+
+            [[stimulus]]
+            at = "1s"
+            push_switch = 0
+
+            [[expect]]
+            at = "1s"
+            port_b = 0xF0
+
+            [[expect]]
+            at = "1.5s"
+            seven_seg = "12 f"
+
+            [[expect]]
+            stdout = "hello"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(scenario.elf.as_deref(), Some("prog.elf"));
+        assert_eq!(scenario.timeout, Some(Duration::from_secs(5)));
+        assert_eq!(
+            scenario.stimuli,
+            vec![
+                Stimulus {
+                    at: 10_000_000,
+                    action: Action::Toggle {
+                        switch: 1,
+                        on: true
+                    }
+                },
+                Stimulus {
+                    at: 20_000_000,
+                    action: Action::Push {
+                        switch: 0,
+                        duration: 2_000_000
+                    }
+                },
+            ]
+        );
+        assert_eq!(
+            scenario.expectations,
+            vec![
+                Expectation {
+                    at: Some(20_000_000),
+                    check: Check::PortB(0xf0)
+                },
+                Expectation {
+                    at: Some(30_000_000),
+                    check: Check::SevenSeg([Some(1), Some(2), None, Some(15)])
+                },
+                Expectation {
+                    at: None,
+                    check: Check::Stdout("hello".to_string())
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_scenarios() {
+        for text in [
+            "unknown = 1",
+            "[[stimulus]]\nat = \"1s\"",
+            "[[stimulus]]\nat = \"1s\"\ntoggle_switch = 5\non = true",
+            "[[stimulus]]\ntoggle_switch = 0\non = true",
+            "[[expect]]\nport_b = 1",
+            "[[expect]]\nat = \"1s\"\nport_b = 256",
+            "[[expect]]\nat = \"1s\"\nseven_seg = \"123\"",
+            "[[expect]]\nat = \"1s\"\nport_b = 1\nstdout = \"a\"",
+            "[[expect]]\nat = \"1 hour\"\nstdout = \"a\"",
+        ] {
+            assert!(Scenario::parse(text).is_err(), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,433 @@
+use super::{
+    format_digits, format_time, Action, Check, CheckResult, Expectation, Report, Scenario,
+};
+use crate::{
+    board,
+    emulator::protocol::{EmulatorEvent, HostCommand},
+};
+use std::collections::VecDeque;
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty()
+        || haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SwitchChange {
+    Toggle { switch: usize, on: bool },
+    Push { switch: usize, pressed: bool },
+}
+
+/// Drives a scenario from the events of a running emulator.
+///
+/// With `stepping`, the emulator is paused and stepped to the time of each
+/// stimulus and expectation. Otherwise stimuli are sent when an event at or
+/// after their time arrives, which is only as exact as the sync interval.
+pub struct ScenarioRunner {
+    /// Sorted by state.
+    changes: VecDeque<(usize, SwitchChange)>,
+    port5: u8,
+    pending: Vec<Expectation>,
+    results: Vec<CheckResult>,
+    stepping: bool,
+    state: usize,
+    port_b: u8,
+    /// Writes that still light their digits, see `board::seven_seg_digits`.
+    port4: VecDeque<(u8, usize)>,
+    /// Bytes, as the program may split a character across writes.
+    stdout: Vec<u8>,
+}
+
+impl ScenarioRunner {
+    pub fn new(scenario: &Scenario, stepping: bool) -> Self {
+        let mut changes = Vec::new();
+        for stimulus in &scenario.stimuli {
+            match stimulus.action {
+                Action::Toggle { switch, on } => {
+                    changes.push((stimulus.at, SwitchChange::Toggle { switch, on }))
+                }
+                Action::Push { switch, duration } => {
+                    changes.push((
+                        stimulus.at,
+                        SwitchChange::Push {
+                            switch,
+                            pressed: true,
+                        },
+                    ));
+                    changes.push((
+                        stimulus.at + duration,
+                        SwitchChange::Push {
+                            switch,
+                            pressed: false,
+                        },
+                    ));
+                }
+            }
+        }
+        // Stable, so changes at the same time keep the scenario order.
+        changes.sort_by_key(|(at, _)| *at);
+
+        Self {
+            changes: changes.into(),
+            port5: board::SWITCHES_RELEASED,
+            pending: scenario.expectations.clone(),
+            results: Vec::new(),
+            stepping,
+            state: 0,
+            // As initialized by the GUI.
+            port_b: 0xff,
+            port4: VecDeque::new(),
+            stdout: Vec::new(),
+        }
+    }
+
+    /// True once every stimulus was sent and every expectation decided.
+    pub fn is_done(&self) -> bool {
+        self.changes.is_empty() && self.pending.is_empty()
+    }
+
+    /// Returns the commands to send in response to `event`.
+    pub fn handle_event(&mut self, event: &EmulatorEvent) -> Vec<HostCommand> {
+        let mut commands = Vec::new();
+        match event {
+            EmulatorEvent::Ready => {
+                self.apply_changes(0, &mut commands);
+                commands.push(HostCommand::IoPort {
+                    port: board::SWITCH_PORT,
+                    value: self.port5,
+                });
+                commands.push(HostCommand::Start);
+                if self.stepping {
+                    commands.push(HostCommand::Pause);
+                }
+            }
+            EmulatorEvent::Stdout(output) => {
+                let mut stdout = std::mem::take(&mut self.stdout);
+                stdout.extend_from_slice(output);
+                self.decide(|expectation| match &expectation.check {
+                    Check::Stdout(text) if contains(&stdout, text.as_bytes()) => Some(Ok(())),
+                    _ => None,
+                });
+                self.stdout = stdout;
+            }
+            EmulatorEvent::IoPort { port, value, state } => {
+                // Decide on the values from before this write.
+                self.advance(state.saturating_sub(1));
+                match *port {
+                    board::LED_PORT => self.port_b = *value,
+                    board::SEVEN_SEG_PORT => self.port4.push_back((*value, *state)),
+                    _ => (),
+                }
+                self.advance(*state);
+                if !self.stepping {
+                    self.apply_changes(*state, &mut commands);
+                }
+            }
+            EmulatorEvent::Sync { state } => {
+                self.advance(*state);
+                if !self.stepping {
+                    self.apply_changes(*state, &mut commands);
+                }
+            }
+            EmulatorEvent::Paused { state } => {
+                self.advance(*state);
+                self.apply_changes(*state, &mut commands);
+                if self.stepping {
+                    commands.push(self.next_step());
+                }
+            }
+            EmulatorEvent::Exit { state, .. } | EmulatorEvent::Crash { state, .. } => {
+                self.advance(*state);
+            }
+            EmulatorEvent::Hello { .. } | EmulatorEvent::Unknown(_) => (),
+        }
+        commands
+    }
+
+    /// Steps to the next stimulus or expectation, or runs freely when only
+    /// stdout expectations without a deadline are left.
+    fn next_step(&self) -> HostCommand {
+        let next = self
+            .changes
+            .front()
+            .map(|(at, _)| *at)
+            .into_iter()
+            .chain(self.pending.iter().filter_map(|e| e.at))
+            .min();
+        match next {
+            Some(at) => HostCommand::Step {
+                states: at.saturating_sub(self.state).max(1),
+            },
+            None => HostCommand::Resume,
+        }
+    }
+
+    fn apply_changes(&mut self, state: usize, commands: &mut Vec<HostCommand>) {
+        let mut changed = false;
+        while let Some((_, change)) = self.changes.front().filter(|(at, _)| *at <= state) {
+            self.port5 = match *change {
+                SwitchChange::Toggle { switch, on } => {
+                    board::set_toggle_switch(self.port5, switch, on)
+                }
+                SwitchChange::Push { switch, pressed } => {
+                    board::set_push_switch(self.port5, switch, pressed)
+                }
+            };
+            self.changes.pop_front();
+            changed = true;
+        }
+        if changed && state > 0 {
+            commands.push(HostCommand::IoPort {
+                port: board::SWITCH_PORT,
+                value: self.port5,
+            });
+        }
+    }
+
+    /// Decides the expectations up to `state`.
+    fn advance(&mut self, state: usize) {
+        self.state = self.state.max(state);
+
+        let port_b = self.port_b;
+        let port4 = &self.port4;
+        let digits_at = |at: usize| {
+            board::seven_seg_digits(port4.iter().filter(|(_, written)| *written <= at), at)
+        };
+        let mut decided = Vec::new();
+        let mut i = 0;
+        while i < self.pending.len() {
+            let Some(at) = self.pending[i].at.filter(|at| *at <= state) else {
+                i += 1;
+                continue;
+            };
+            let outcome = match &self.pending[i].check {
+                Check::PortB(value) if *value == port_b => Ok(()),
+                Check::PortB(_) => Err(format!("was 0x{:02x}", port_b)),
+                Check::SevenSeg(expected) => {
+                    let digits = digits_at(at);
+                    if *expected == digits {
+                        Ok(())
+                    } else {
+                        Err(format!("was {:?}", format_digits(&digits)))
+                    }
+                }
+                Check::Stdout(_) => Err(format!("not printed by {}", format_time(at))),
+            };
+            decided.push(CheckResult {
+                expectation: self.pending.remove(i),
+                outcome,
+            });
+        }
+        self.results.extend(decided);
+
+        while self.port4.len() > 1
+            && self
+                .port4
+                .front()
+                .is_some_and(|(_, at)| !board::seven_seg_held(*at, state))
+        {
+            self.port4.pop_front();
+        }
+    }
+
+    /// Moves the expectations `check` returns an outcome for to the results.
+    fn decide(&mut self, check: impl Fn(&Expectation) -> Option<Result<(), String>>) {
+        let mut i = 0;
+        while i < self.pending.len() {
+            match check(&self.pending[i]) {
+                Some(outcome) => self.results.push(CheckResult {
+                    expectation: self.pending.remove(i),
+                    outcome,
+                }),
+                None => i += 1,
+            }
+        }
+    }
+
+    /// Fails the undecided expectations and returns the report.
+    pub fn finish(mut self, error: Option<String>) -> Report {
+        let reason = match &error {
+            Some(_) => "not reached".to_string(),
+            None => format!("program ended at {}", format_time(self.state)),
+        };
+        self.decide(|expectation| match expectation.check {
+            Check::Stdout(_) if expectation.at.is_none() => Some(Err("not printed".to_string())),
+            _ => Some(Err(reason.clone())),
+        });
+        Report {
+            results: self.results,
+            error,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scenario(text: &str) -> Scenario {
+        Scenario::parse(text).unwrap()
+    }
+
+    fn outcomes(report: &Report) -> Vec<bool> {
+        report.results.iter().map(|r| r.outcome.is_ok()).collect()
+    }
+
+    #[test]
+    fn steps_to_stimuli_and_expectations() {
+        let mut runner = ScenarioRunner::new(
+            &scenario(
+                "[[stimulus]]\nat = \"0.5s\"\ntoggle_switch = 1\non = true\n\
+                 [[expect]]\nat = \"1s\"\nport_b = 0xf0\n",
+            ),
+            true,
+        );
+
+        assert_eq!(
+            runner.handle_event(&EmulatorEvent::Ready),
+            vec![
+                HostCommand::IoPort {
+                    port: 5,
+                    value: 0x3
+                },
+                HostCommand::Start,
+                HostCommand::Pause
+            ]
+        );
+        assert_eq!(
+            runner.handle_event(&EmulatorEvent::Paused { state: 100 }),
+            vec![HostCommand::Step {
+                states: 10_000_000 - 100
+            }]
+        );
+        assert_eq!(
+            runner.handle_event(&EmulatorEvent::Paused { state: 10_000_000 }),
+            vec![
+                HostCommand::IoPort {
+                    port: 5,
+                    value: 0xb
+                },
+                HostCommand::Step { states: 10_000_000 }
+            ]
+        );
+        runner.handle_event(&EmulatorEvent::IoPort {
+            port: 0xb,
+            value: 0xf0,
+            state: 15_000_000,
+        });
+        runner.handle_event(&EmulatorEvent::Paused { state: 20_000_000 });
+
+        assert!(runner.is_done());
+        assert_eq!(outcomes(&runner.finish(None)), vec![true]);
+    }
+
+    #[test]
+    fn checks_values_at_the_expected_time() {
+        let mut runner = ScenarioRunner::new(
+            &scenario(
+                "[[expect]]\nat = \"1s\"\nport_b = 0x0f\n\
+                 [[expect]]\nat = \"2s\"\nport_b = 0x0f\n\
+                 [[expect]]\nat = \"2s\"\nseven_seg = \" 3  \"\n",
+            ),
+            false,
+        );
+
+        runner.handle_event(&EmulatorEvent::IoPort {
+            port: 0xb,
+            value: 0x0f,
+            state: 10_000_000,
+        });
+        runner.handle_event(&EmulatorEvent::IoPort {
+            port: 0x4,
+            value: 0x32,
+            state: 39_900_000,
+        });
+        // Written after 2s, so the expectation at 2s sees 0x0f.
+        runner.handle_event(&EmulatorEvent::IoPort {
+            port: 0xb,
+            value: 0xff,
+            state: 40_000_001,
+        });
+
+        assert!(runner.is_done());
+        assert_eq!(outcomes(&runner.finish(None)), vec![true, true, true]);
+    }
+
+    #[test]
+    fn keeps_digits_lit_for_the_hold_time() {
+        let mut runner = ScenarioRunner::new(
+            &scenario(
+                "[[expect]]\nat = \"1.02s\"\nseven_seg = \"78  \"\n\
+                 [[expect]]\nat = \"1.1s\"\nseven_seg = \" 8  \"\n",
+            ),
+            false,
+        );
+
+        runner.handle_event(&EmulatorEvent::IoPort {
+            port: 0x4,
+            value: 0x71,
+            state: 20_000_000,
+        });
+        runner.handle_event(&EmulatorEvent::IoPort {
+            port: 0x4,
+            value: 0x82,
+            state: 20_200_000,
+        });
+        runner.handle_event(&EmulatorEvent::Sync { state: 30_000_000 });
+
+        assert_eq!(outcomes(&runner.finish(None)), vec![true, true]);
+    }
+
+    #[test]
+    fn presses_and_releases_push_switches() {
+        let mut runner = ScenarioRunner::new(
+            &scenario("[[stimulus]]\nat = \"1s\"\npush_switch = 1\nduration = \"1s\"\n"),
+            false,
+        );
+
+        runner.handle_event(&EmulatorEvent::Ready);
+        assert_eq!(
+            runner.handle_event(&EmulatorEvent::Sync { state: 20_000_000 }),
+            vec![HostCommand::IoPort {
+                port: 5,
+                value: 0x1
+            }]
+        );
+        assert_eq!(
+            runner.handle_event(&EmulatorEvent::Sync { state: 40_000_000 }),
+            vec![HostCommand::IoPort {
+                port: 5,
+                value: 0x3
+            }]
+        );
+        assert!(runner.is_done());
+    }
+
+    #[test]
+    fn fails_undecided_expectations_on_finish() {
+        let mut runner = ScenarioRunner::new(
+            &scenario(
+                "[[expect]]\nstdout = \"hello\"\n\
+                 [[expect]]\nstdout = \"bye\"\nat = \"1s\"\n\
+                 [[expect]]\nstdout = \"never\"\n\
+                 [[expect]]\nat = \"5s\"\nport_b = 0xff\n",
+            ),
+            false,
+        );
+
+        runner.handle_event(&EmulatorEvent::Stdout(b"hel".to_vec()));
+        runner.handle_event(&EmulatorEvent::Stdout(b"lo\n".to_vec()));
+        runner.handle_event(&EmulatorEvent::Sync { state: 30_000_000 });
+        runner.handle_event(&EmulatorEvent::Stdout(b"bye\n".to_vec()));
+        runner.handle_event(&EmulatorEvent::Exit {
+            code: 0,
+            state: 40_000_000,
+        });
+
+        let report = runner.finish(None);
+        assert_eq!(outcomes(&report), vec![true, false, false, false]);
+        assert!(!report.passed());
+    }
+}
@@ -1,28 +1,60 @@
-use crate::emulator::{self, Emulator};
+use crate::emulator::{
+    self,
+    protocol::{Capability, HostCommand},
+    Emulator, EmulatorError, RunSummary, TerminationReason, CLOCK_FREQUENCY, STOP_TIMEOUT,
+};
 use eframe::egui;
+use elf_watcher::ElfWatcher;
+use emulator_log::EmulatorLog;
 use ioport::IoPort;
 use message_window::MessageWindow;
-use std::time;
+use std::{path::PathBuf, time};
 use terminal::Terminal;
+use timeline_window::TimelineWindow;
 use tokio::sync::mpsc::{self, Receiver};
 use views::SimulatorUiStates;
 
+mod elf_watcher;
+mod emulator_log;
 mod ioport;
 mod message_window;
 mod parse_messages;
 mod terminal;
+mod timeline_window;
 mod views;
 
 pub struct Simulator {
     emulator: Option<Emulator>,
-    emulator_exec_rx: Option<Receiver<Result<Emulator, String>>>,
+    emulator_exec_rx: Option<Receiver<Result<Emulator, EmulatorError>>>,
+    emulator_error: Option<EmulatorError>,
+    /// Set by `exit` or `crash` messages of the current run.
+    termination: Option<TerminationReason>,
+    stop_requested: bool,
+    /// When a stopping emulator is killed (or detached, if attached).
+    stop_deadline: Option<time::Instant>,
+    /// Execute again once the current emulator has stopped.
+    restart_requested: bool,
+    /// The elf changed while the emulator was starting, restart it once it
+    /// has.
+    restart_pending: bool,
+    run_summary: Option<RunSummary>,
     speed: f64,
     ui_states: SimulatorUiStates,
     message_window: MessageWindow,
+    emulator_log: EmulatorLog,
+    timeline_window: TimelineWindow,
+    elf_watcher: ElfWatcher,
     terminal: Terminal,
     io_port: IoPort,
     sync_timing: time::Instant,
     emulator_state: usize,
+    paused: bool,
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Simulator {
@@ -30,81 +62,326 @@ impl Simulator {
         let mut simulator = Simulator {
             emulator: None,
             emulator_exec_rx: None,
+            emulator_error: None,
+            termination: None,
+            stop_requested: false,
+            stop_deadline: None,
+            restart_requested: false,
+            restart_pending: false,
+            run_summary: None,
             speed: 0f64,
             ui_states: SimulatorUiStates::new(),
             message_window: MessageWindow::new(),
+            emulator_log: EmulatorLog::new(),
+            timeline_window: TimelineWindow::new(),
+            elf_watcher: ElfWatcher::new(),
             terminal: Terminal::new(),
             io_port: IoPort::new(),
             sync_timing: time::Instant::now(),
             emulator_state: 0,
+            paused: false,
         };
         simulator.io_port.init_led();
         simulator.io_port.init_switches();
         simulator
     }
 
-    fn update(&mut self) {
+    fn update(&mut self, ctx: &egui::Context) {
         if let Some(rx) = self.emulator_exec_rx.as_mut() {
             if let Ok(result) = rx.try_recv() {
                 match result {
                     Ok(emulator) => self.emulator = Some(emulator),
-                    Err(e) => println!("{}", e),
+                    Err(e) => {
+                        log::error!("{}", e);
+                        self.emulator_error = Some(e);
+                    }
+                }
+                self.emulator_exec_rx = None;
+                if self.restart_pending {
+                    self.restart_pending = false;
+                    self.restart_emulator(ctx);
                 }
-                self.emulator_exec_rx = None
             }
         }
 
         // Finished emulator
         if let Some(emulator) = self.emulator.as_mut() {
-            if emulator.socket_receiver_handle.is_finished() && emulator.process.try_wait().is_ok()
-            {
+            if emulator.is_finished() {
                 self.pop_emulator_messages();
-                self.emulator = None;
-                self.io_port.init_led();
+                self.finish_emulator(None);
+            } else if self
+                .stop_deadline
+                .is_some_and(|d| d <= time::Instant::now())
+            {
+                self.stop_deadline = None;
+                if emulator.is_attached() {
+                    log::warn!("Attached emulator did not stop, detaching.");
+                    self.detach_emulator();
+                } else {
+                    log::warn!("Emulator did not stop, killing it.");
+                    emulator.kill();
+                }
+            } else if emulator.socket_receiver_handle.is_finished() || self.stop_requested {
+                // Wait for the process to exit or the stop timeout.
+                ctx.request_repaint_after(std::time::Duration::from_millis(100));
             }
         }
 
+        let replay_path = self.ui_states.replay_path.lock().unwrap().take();
+        if let Some(path) = replay_path {
+            if self.emulator.is_none() && self.emulator_exec_rx.is_none() {
+                self.replay_session(path, ctx);
+            }
+        }
+
+        let elf_path = self.ui_states.elf_path.lock().unwrap().clone();
+        if self.elf_watcher.poll(&elf_path) {
+            self.restart_emulator(ctx);
+        }
+        if self.elf_watcher.enabled {
+            ctx.request_repaint_after(elf_watcher::POLL_INTERVAL);
+        }
+        if self.restart_requested && self.emulator.is_none() {
+            self.restart_requested = false;
+            self.execute_emulator(ctx);
+        }
+
         self.pop_emulator_messages();
+        self.emulator_log.pop_lines();
+    }
+
+    /// Executes the elf again, stopping the running emulator first.
+    fn restart_emulator(&mut self, ctx: &egui::Context) {
+        if self.emulator_exec_rx.is_some() {
+            self.restart_pending = true;
+            return;
+        }
+        match &self.emulator {
+            Some(emulator) if emulator.is_attached() => {
+                log::info!("Elf changed, but the emulator is attached. Not restarting.");
+            }
+            Some(_) => {
+                log::info!("Elf changed, restarting emulator.");
+                if !self.stop_requested {
+                    self.stop_emulator();
+                }
+                self.restart_requested = true;
+            }
+            None => {
+                log::info!("Elf changed, executing emulator.");
+                self.execute_emulator(ctx);
+            }
+        }
     }
 
     fn execute_emulator(&mut self, ctx: &egui::Context) {
-        self.emulator_state = 0;
-        self.speed = 1.0f64;
-        self.io_port.init_led();
-        self.message_window.clear_messages();
-        self.terminal.clear();
-        self.ui_states.speed = 0f64;
+        self.reset_for_start();
 
         let (tx, rx) = mpsc::channel(1);
         self.emulator_exec_rx = Some(rx);
         let _elf_path = self.ui_states.elf_path.lock().unwrap().clone();
         let _elf_args = self.ui_states.elf_args.clone();
+        let _port = if self.ui_states.use_fixed_port {
+            Some(self.ui_states.fixed_port)
+        } else {
+            None
+        };
+        let _session_path = self.session_path();
+        let _log_tx = self.emulator_log.start();
+        let _ctx = ctx.clone();
+        tokio::spawn(async move {
+            let emu = emulator::Emulator::execute(
+                _elf_path,
+                _elf_args,
+                _port,
+                _session_path.as_deref(),
+                _log_tx,
+                _ctx,
+            )
+            .await;
+            if let Err(e) = tx.send(emu).await {
+                eprintln!("{}", e)
+            }
+        });
+    }
+
+    fn attach_emulator(&mut self, ctx: &egui::Context) {
+        self.reset_for_start();
+
+        let (tx, rx) = mpsc::channel(1);
+        self.emulator_exec_rx = Some(rx);
+        let _addr = self.ui_states.attach_addr.trim().to_string();
+        let _session_path = self.session_path();
         let _ctx = ctx.clone();
         tokio::spawn(async move {
-            let emu = emulator::Emulator::execute(_elf_path, _elf_args, _ctx).await;
+            let emu = emulator::Emulator::attach(_addr, _session_path.as_deref(), _ctx).await;
             if let Err(e) = tx.send(emu).await {
                 eprintln!("{}", e)
             }
         });
     }
 
-    fn stop_emulator(&self) {
+    fn replay_session(&mut self, path: PathBuf, ctx: &egui::Context) {
+        self.reset_for_start();
+
+        let (tx, rx) = mpsc::channel(1);
+        self.emulator_exec_rx = Some(rx);
+        let _ctx = ctx.clone();
+        tokio::spawn(async move {
+            let emu = emulator::Emulator::replay(path, _ctx).await;
+            if let Err(e) = tx.send(emu).await {
+                eprintln!("{}", e)
+            }
+        });
+    }
+
+    /// Where to record the next session, if recording is enabled.
+    fn session_path(&self) -> Option<PathBuf> {
+        if !self.ui_states.record_session {
+            return None;
+        }
+        Some(PathBuf::from(self.ui_states.session_path.trim()))
+    }
+
+    /// Drops the connection without stopping the attached emulator.
+    fn detach_emulator(&mut self) {
+        self.pop_emulator_messages();
+        self.finish_emulator(Some(TerminationReason::Detached));
+    }
+
+    fn finish_emulator(&mut self, reason: Option<TerminationReason>) {
+        let Some(mut emulator) = self.emulator.take() else {
+            return;
+        };
+        let process_status = emulator.exit_status();
+        let reason = reason.or(self.termination.take()).unwrap_or({
+            if self.stop_requested {
+                TerminationReason::StoppedByUser
+            } else {
+                TerminationReason::EmulatorDied
+            }
+        });
+        log::info!("{}", reason);
+        self.run_summary = Some(RunSummary {
+            reason,
+            process_status,
+            state: self.emulator_state,
+        });
+        self.paused = false;
+        self.stop_deadline = None;
+        self.io_port.init_led();
+    }
+
+    fn reset_for_start(&mut self) {
+        self.emulator_state = 0;
+        self.emulator_error = None;
+        self.termination = None;
+        self.stop_requested = false;
+        self.stop_deadline = None;
+        self.run_summary = None;
+        self.paused = false;
+        self.speed = 1.0f64;
+        self.io_port.init_led();
+        self.io_port.clear_history();
+        self.message_window.clear_messages();
+        self.terminal.clear();
+        self.ui_states.speed = 0f64;
+    }
+
+    fn stop_emulator(&mut self) {
+        self.restart_requested = false;
+        self.restart_pending = false;
         if let Some(emulator) = &self.emulator {
-            emulator.send_message("cmd:stop");
+            emulator.send_message(HostCommand::Stop);
+            self.stop_requested = true;
+            self.stop_deadline = Some(time::Instant::now() + STOP_TIMEOUT);
         };
     }
 
+    fn is_stopping(&self) -> bool {
+        self.emulator.is_some() && self.stop_requested
+    }
+
+    /// Stops the emulator before the application exits, killing it if it
+    /// does not stop within `STOP_TIMEOUT`. An attached emulator is left
+    /// running.
+    pub fn shutdown(&mut self) {
+        let Some(emulator) = self.emulator.take() else {
+            return;
+        };
+        if emulator.is_attached() {
+            return;
+        }
+        log::info!("Stopping emulator...");
+        let status = tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current().block_on(emulator.shutdown(STOP_TIMEOUT))
+        });
+        if let Some(status) = status {
+            log::info!("Emulator exited: {}", status);
+        }
+    }
+
+    /// Returns false while no emulator is running.
+    fn emulator_supports(&self, capability: Capability) -> bool {
+        self.emulator
+            .as_ref()
+            .is_some_and(|emulator| emulator.supports(capability))
+    }
+
+    fn pause_emulator(&mut self) {
+        if let Some(emulator) = &self.emulator {
+            emulator.send_message(HostCommand::Pause);
+            // Freeze at the estimated state until `paused` reports the exact one.
+            self.emulator_state = self.get_corrected_current_emulator_state();
+            self.paused = true;
+        }
+    }
+
+    fn resume_emulator(&mut self) {
+        if let Some(emulator) = &self.emulator {
+            emulator.send_message(HostCommand::Resume);
+            self.paused = false;
+            self.sync_timing = time::Instant::now();
+        }
+    }
+
+    fn step_emulator(&self, states: usize) {
+        if let Some(emulator) = &self.emulator {
+            emulator.send_message(HostCommand::Step { states });
+        }
+    }
+
     fn send_initial_ioport(&self, emulator: &Emulator) {
+        if !emulator.supports(Capability::IoPort) {
+            return;
+        }
+
         // Switch
-        emulator.send_message(format!(
-            "ioport:{:x}:{:x}",
-            0x5,
-            self.io_port.read(0x5).unwrap()
-        ));
+        emulator.send_message(HostCommand::IoPort {
+            port: 0x5,
+            value: self.io_port.read(0x5).unwrap(),
+        });
+    }
+
+    fn send_speed_limit(&self, emulator: &Emulator) {
+        if emulator.supports(Capability::Speed) {
+            emulator.send_message(HostCommand::Speed(self.ui_states.speed_limit));
+        }
+    }
+
+    fn send_terminal_input(&mut self) {
+        let input = self.terminal.take_input();
+        if let Some(emulator) = &self.emulator {
+            for text in input {
+                emulator.send_message(HostCommand::Stdin(text));
+            }
+        }
     }
 
     fn pop_emulator_messages(&mut self) {
         if let Some(emulator) = self.emulator.as_mut() {
+            self.message_window
+                .push_sent_messages(&emulator.pop_sent_messages());
             let messages = emulator.pop_messages();
             self.message_window.push_messages(&messages);
 
@@ -115,7 +392,15 @@ impl Simulator {
     }
 
     fn get_corrected_current_emulator_state(&self) -> usize {
-        let elapsed_from_1sec = self.sync_timing.elapsed().as_secs_f64() * self.speed;
-        self.emulator_state + (elapsed_from_1sec * 20_000_000f64) as usize
+        if self.paused || self.emulator.is_none() {
+            return self.emulator_state;
+        }
+        // The measured speed lags behind a newly set limit until the next sync.
+        let speed = match self.ui_states.speed_limit {
+            Some(limit) => self.speed.min(limit),
+            None => self.speed,
+        };
+        let elapsed_from_1sec = self.sync_timing.elapsed().as_secs_f64() * speed;
+        self.emulator_state + (elapsed_from_1sec * CLOCK_FREQUENCY as f64) as usize
     }
 }
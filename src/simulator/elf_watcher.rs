@@ -0,0 +1,109 @@
+use std::time::{Duration, Instant, SystemTime};
+
+pub const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// How long the file must stay unchanged before it counts as rewritten, so
+/// a build still writing it is not picked up halfway.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Modification time and size of the watched file.
+type FileStamp = (SystemTime, u64);
+
+fn read_stamp(path: &str) -> Option<FileStamp> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Polls the elf file for changes.
+pub struct ElfWatcher {
+    pub enabled: bool,
+    path: String,
+    stamp: Option<FileStamp>,
+    changed_at: Option<Instant>,
+    polled_at: Instant,
+}
+
+impl ElfWatcher {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            path: String::new(),
+            stamp: None,
+            changed_at: None,
+            polled_at: Instant::now(),
+        }
+    }
+
+    /// Returns true once after `path` has been rewritten and then left
+    /// unchanged for `DEBOUNCE`. Changing the path or enabling the watcher
+    /// takes the current file as the baseline.
+    pub fn poll(&mut self, path: &str) -> bool {
+        if !self.enabled {
+            self.path.clear();
+            return false;
+        }
+        if self.path != path {
+            self.path = path.to_string();
+            self.stamp = read_stamp(path);
+            self.changed_at = None;
+            return false;
+        }
+        if self.polled_at.elapsed() < POLL_INTERVAL {
+            return false;
+        }
+        self.polled_at = Instant::now();
+
+        let stamp = read_stamp(path);
+        if stamp != self.stamp {
+            // A removed file is still being rebuilt.
+            self.changed_at = stamp.map(|_| Instant::now());
+            self.stamp = stamp;
+            return false;
+        }
+        if self.changed_at.is_some_and(|t| t.elapsed() >= DEBOUNCE) {
+            self.changed_at = None;
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    fn poll_for(watcher: &mut ElfWatcher, path: &str, duration: Duration) -> usize {
+        let start = Instant::now();
+        let mut count = 0;
+        while start.elapsed() < duration {
+            if watcher.poll(path) {
+                count += 1;
+            }
+            sleep(Duration::from_millis(20));
+        }
+        count
+    }
+
+    #[test]
+    fn reports_rewrite_once_after_debounce() {
+        let path = std::env::temp_dir().join(format!("h8pks-watch-{}.elf", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        std::fs::write(&path, "old").unwrap();
+
+        let mut watcher = ElfWatcher::new();
+        watcher.enabled = true;
+        assert!(!watcher.poll(path_str));
+        assert_eq!(poll_for(&mut watcher, path_str, POLL_INTERVAL * 2), 0);
+
+        std::fs::write(&path, "new binary").unwrap();
+        assert_eq!(
+            poll_for(&mut watcher, path_str, POLL_INTERVAL * 2 + DEBOUNCE * 2),
+            1
+        );
+
+        watcher.enabled = false;
+        std::fs::write(&path, "ignored").unwrap();
+        assert_eq!(poll_for(&mut watcher, path_str, POLL_INTERVAL * 2), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
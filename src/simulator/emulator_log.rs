@@ -0,0 +1,112 @@
+use crate::emulator::{LogLine, LogStream};
+use eframe::egui;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+const MAX_LOG_LEN: usize = 5000;
+
+/// Output of the emulator process, kept across runs so crashes can be read
+/// after the process is gone.
+pub struct EmulatorLog {
+    lines: Vec<LogLine>,
+    log_rx: Option<Receiver<LogLine>>,
+    pub is_opened_log_window: bool,
+}
+
+impl EmulatorLog {
+    pub fn new() -> Self {
+        Self {
+            lines: Vec::new(),
+            log_rx: None,
+            is_opened_log_window: false,
+        }
+    }
+
+    /// Starts a new section of the log and returns the sender for the next
+    /// emulator process.
+    pub fn start(&mut self) -> Sender<LogLine> {
+        self.pop_lines();
+        if !self.lines.is_empty() {
+            self.push_line(LogLine {
+                stream: LogStream::Stdout,
+                text: "----".to_string(),
+            });
+        }
+        let (log_tx, log_rx) = channel(64);
+        self.log_rx = Some(log_rx);
+        log_tx
+    }
+
+    pub fn pop_lines(&mut self) {
+        let mut lines = Vec::new();
+        if let Some(rx) = self.log_rx.as_mut() {
+            while let Ok(line) = rx.try_recv() {
+                lines.push(line);
+            }
+        }
+        for line in lines {
+            self.push_line(line);
+        }
+    }
+
+    fn push_line(&mut self, line: LogLine) {
+        self.lines.push(line);
+        if self.lines.len() > MAX_LOG_LEN {
+            self.lines.drain(..self.lines.len() - MAX_LOG_LEN);
+        }
+    }
+
+    pub fn show_window(&mut self, ctx: &egui::Context) {
+        if !self.is_opened_log_window {
+            return;
+        }
+        ctx.show_viewport_immediate(
+            egui::ViewportId::from_hash_of("emulator_log_window"),
+            egui::ViewportBuilder::default()
+                .with_title("Emulator Log")
+                .with_inner_size([480.0, 320.0]),
+            |ctx, _class| {
+                egui::CentralPanel::default().show(ctx, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label(format!("line: {}", self.lines.len()));
+                        if ui.button("Clear").clicked() {
+                            self.lines.clear();
+                        }
+                    });
+                    ui.separator();
+
+                    let text_style = egui::TextStyle::Monospace;
+                    let row_height = ui.text_style_height(&text_style);
+                    let error_color = ui.visuals().error_fg_color;
+                    egui::ScrollArea::vertical()
+                        .stick_to_bottom(true)
+                        .auto_shrink(false)
+                        .show_rows(ui, row_height, self.lines.len(), |ui, row_range| {
+                            for row in row_range {
+                                let line = &self.lines[row];
+                                let text = egui::RichText::new(&line.text).monospace();
+                                match line.stream {
+                                    LogStream::Stdout => ui.label(text),
+                                    LogStream::Stderr => ui.label(text.color(error_color)),
+                                };
+                            }
+                        });
+
+                    if ctx.input(|i| i.viewport().close_requested()) {
+                        self.is_opened_log_window = false;
+                    }
+                });
+            },
+        )
+    }
+
+    pub fn show_open_close_button(&mut self, ui: &mut egui::Ui) {
+        let text = if self.is_opened_log_window {
+            "Close emulator log"
+        } else {
+            "Open emulator log"
+        };
+        if ui.button(text).clicked() {
+            self.is_opened_log_window = !self.is_opened_log_window
+        }
+    }
+}
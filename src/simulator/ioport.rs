@@ -1,7 +1,22 @@
+use crate::{
+    board::{self, PortWrite},
+    vcd,
+};
+use std::{collections::VecDeque, io};
+
+/// Older writes are dropped from the history beyond this, by default.
+pub const DEFAULT_HISTORY_LIMIT: usize = 1_000_000;
+
 pub struct IoPort {
     port4: Vec<(u8, usize)>,
     port5: u8,
     portb: u8,
+    /// Writes to `board::PORTS` since the history was last cleared, in
+    /// state order.
+    history: VecDeque<PortWrite>,
+    /// Values of `board::PORTS` before the first write in `history`.
+    history_start: [u8; 3],
+    history_limit: usize,
 }
 
 impl IoPort {
@@ -10,6 +25,9 @@ impl IoPort {
             port4: Vec::new(),
             port5: 0,
             portb: 0,
+            history: VecDeque::new(),
+            history_start: [0; 3],
+            history_limit: DEFAULT_HISTORY_LIMIT,
         }
     }
 
@@ -22,6 +40,13 @@ impl IoPort {
     }
 
     pub fn write(&mut self, port: u8, value: u8, emulator_state: usize) {
+        if board::port_index(port).is_some() {
+            self.push_history(PortWrite {
+                state: emulator_state,
+                port,
+                value,
+            });
+        }
         return match port {
             4 => self.port4.push((value, emulator_state)),
             5 => self.port5 = value,
@@ -30,18 +55,63 @@ impl IoPort {
         };
     }
 
-    pub fn filter_port4(&mut self, threshold_state: usize) {
+    fn push_history(&mut self, mut write: PortWrite) {
+        // Host writes are stamped with an estimated state, which can run
+        // ahead of the emulator's next write.
+        if let Some(last) = self.history.back() {
+            write.state = write.state.max(last.state);
+        }
+        self.history.push_back(write);
+        self.trim_history();
+    }
+
+    fn trim_history(&mut self) {
+        while self.history.len() > self.history_limit {
+            if let Some(dropped) = self.history.pop_front() {
+                self.history_start[board::port_index(dropped.port).unwrap()] = dropped.value;
+            }
+        }
+    }
+
+    /// Sets how many writes the history keeps, dropping the oldest ones.
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.history_limit = limit;
+        self.trim_history();
+    }
+
+    pub fn history(&self) -> &VecDeque<PortWrite> {
+        &self.history
+    }
+
+    /// Values of `board::PORTS` before the first write of `history`.
+    pub fn history_start(&self) -> [u8; 3] {
+        self.history_start
+    }
+
+    /// Starts the history over from the current port values.
+    pub fn clear_history(&mut self) {
+        self.history.clear();
+        self.history_start = [
+            self.port4.last().map_or(0, |(value, _)| *value),
+            self.port5,
+            self.portb,
+        ];
+    }
+
+    /// Writes the history as a Value Change Dump.
+    pub fn export_vcd(&self, out: &mut impl io::Write) -> io::Result<()> {
+        vcd::write_vcd(out, self.history_start, &self.history)
+    }
+
+    /// Drops the port 4 writes that no longer light their digits at `state`.
+    pub fn filter_port4(&mut self, state: usize) {
         let latest = match self.port4.last() {
             Some(latest) => *latest,
             None => return,
         };
 
-        self.port4 = self
-            .port4
-            .clone()
-            .into_iter()
-            .filter(|port| port.1 >= threshold_state)
-            .collect();
+        self.port4
+            .retain(|(_, written)| board::seven_seg_held(*written, state));
 
         if self.port4.is_empty() {
             self.port4.push(latest);
@@ -54,13 +124,81 @@ impl IoPort {
 
     pub fn init_led(&mut self) {
         // LED
-        self.write(0xb, 0xff, 0);
+        self.portb = 0xff;
 
         // 7Seg LED
         self.port4.clear();
     }
 
     pub fn init_switches(&mut self) {
-        self.write(0x5, 0x3, 0);
+        self.write(board::SWITCH_PORT, board::SWITCHES_RELEASED, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trims_history_to_limit() {
+        let mut io_port = IoPort::new();
+        io_port.init_led();
+        io_port.clear_history();
+        io_port.write(0xb, 0xfe, 10);
+        io_port.write(0x4, 0x31, 20);
+        io_port.write(0xb, 0xfc, 30);
+
+        io_port.set_history_limit(1);
+        assert_eq!(io_port.history_start(), [0x31, 0x00, 0xfe]);
+        assert_eq!(
+            io_port.history().iter().collect::<Vec<_>>(),
+            [&PortWrite {
+                state: 30,
+                port: 0xb,
+                value: 0xfc
+            }]
+        );
+    }
+
+    #[test]
+    fn keeps_history_in_state_order() {
+        let mut io_port = IoPort::new();
+        io_port.write(board::SWITCH_PORT, 0xfe, 200);
+        io_port.write(0xb, 0xf0, 150);
+
+        assert_eq!(
+            io_port.history().iter().collect::<Vec<_>>(),
+            [
+                &PortWrite {
+                    state: 200,
+                    port: board::SWITCH_PORT,
+                    value: 0xfe
+                },
+                &PortWrite {
+                    state: 200,
+                    port: 0xb,
+                    value: 0xf0
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn exports_out_of_order_writes_in_time_order() {
+        let mut io_port = IoPort::new();
+        io_port.init_led();
+        io_port.clear_history();
+        io_port.write(board::SWITCH_PORT, 0x07, 200);
+        io_port.write(0xb, 0xfe, 150);
+
+        let mut out = Vec::new();
+        io_port.export_vcd(&mut out).unwrap();
+        let vcd = String::from_utf8(out).unwrap();
+        let changes = vcd.split("$dumpvars\n").nth(1).unwrap();
+        let times: Vec<_> = changes
+            .lines()
+            .filter_map(|line| line.strip_prefix('#'))
+            .collect();
+        assert_eq!(times, ["10000"]);
     }
 }
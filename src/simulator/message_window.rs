@@ -1,9 +1,86 @@
+use crate::emulator::session::Direction;
 use eframe::egui;
+use regex::Regex;
+use rfd::AsyncFileDialog;
+use std::time::{Duration, Instant};
 
 const MAX_MESSAGE_LEN: usize = 5000;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MessageKind {
+    Stdout,
+    IoPort,
+    Sync,
+    Ready,
+    Outgoing,
+    Other,
+}
+
+const MESSAGE_KINDS: [(MessageKind, &str); 6] = [
+    (MessageKind::Stdout, "stdout"),
+    (MessageKind::IoPort, "ioport"),
+    (MessageKind::Sync, "sync"),
+    (MessageKind::Ready, "ready"),
+    (MessageKind::Outgoing, "outgoing"),
+    (MessageKind::Other, "other"),
+];
+
+impl MessageKind {
+    fn of(direction: Direction, message: &str) -> Self {
+        if direction == Direction::Out {
+            return MessageKind::Outgoing;
+        }
+        match message.split(':').next() {
+            Some("stdout") => MessageKind::Stdout,
+            Some("ioport") => MessageKind::IoPort,
+            Some("sync") => MessageKind::Sync,
+            Some("ready") => MessageKind::Ready,
+            _ => MessageKind::Other,
+        }
+    }
+}
+
+/// Shows bytes that are not valid UTF-8, such as Shift_JIS output of the
+/// program, as `\xNN`.
+fn escape_invalid_utf8(message: &[u8]) -> String {
+    let mut escaped = String::with_capacity(message.len());
+    for chunk in message.utf8_chunks() {
+        escaped.push_str(chunk.valid());
+        for b in chunk.invalid() {
+            escaped.push_str(&format!("\\x{:02x}", b));
+        }
+    }
+    escaped
+}
+
+struct Message {
+    /// Since the messages were last cleared.
+    time: Duration,
+    direction: Direction,
+    kind: MessageKind,
+    /// With newlines escaped, so that every message is one row.
+    text: String,
+}
+
+impl Message {
+    fn format(&self) -> String {
+        let marker = match self.direction {
+            Direction::In => "←",
+            Direction::Out => "→",
+        };
+        format!("{:>9.3} {} {}", self.time.as_secs_f64(), marker, self.text)
+    }
+}
+
 pub struct MessageWindow {
-    messages: Vec<String>,
+    messages: Vec<Message>,
+    start: Instant,
+    shown_kinds: Vec<MessageKind>,
+    search: String,
+    use_regex: bool,
+    /// Compiled from `search` when `use_regex` is set.
+    search_regex: Option<Result<Regex, regex::Error>>,
+    pause_scroll: bool,
     pub is_opened_message_window: bool,
 }
 
@@ -11,13 +88,37 @@ impl MessageWindow {
     pub fn new() -> Self {
         Self {
             messages: Vec::new(),
+            start: Instant::now(),
+            shown_kinds: MESSAGE_KINDS.iter().map(|(kind, _)| *kind).collect(),
+            search: String::new(),
+            use_regex: false,
+            search_regex: None,
+            pause_scroll: false,
             is_opened_message_window: false,
         }
     }
 
-    pub fn push_messages(&mut self, messages: &Vec<String>) {
-        self.messages
-            .extend(messages.iter().map(|s| s.replace('\n', "\\n")));
+    /// Adds messages received from the emulator.
+    pub fn push_messages(&mut self, messages: &[Vec<u8>]) {
+        self.push(
+            Direction::In,
+            messages.iter().map(|m| escape_invalid_utf8(m)),
+        );
+    }
+
+    /// Adds commands sent to the emulator.
+    pub fn push_sent_messages(&mut self, messages: &[String]) {
+        self.push(Direction::Out, messages.iter().cloned());
+    }
+
+    fn push(&mut self, direction: Direction, messages: impl Iterator<Item = String>) {
+        let time = self.start.elapsed();
+        self.messages.extend(messages.map(|s| Message {
+            time,
+            direction,
+            kind: MessageKind::of(direction, &s),
+            text: s.replace('\n', "\\n"),
+        }));
         if self.messages.len() > MAX_MESSAGE_LEN {
             self.messages.drain(..self.messages.len() - MAX_MESSAGE_LEN);
         }
@@ -25,6 +126,74 @@ impl MessageWindow {
 
     pub fn clear_messages(&mut self) {
         self.messages.clear();
+        self.start = Instant::now();
+    }
+
+    fn matches(&self, message: &Message) -> bool {
+        if !self.shown_kinds.contains(&message.kind) {
+            return false;
+        }
+        if self.search.is_empty() {
+            return true;
+        }
+        match &self.search_regex {
+            Some(Ok(regex)) => regex.is_match(&message.text),
+            // Show everything until the pattern is valid.
+            Some(Err(_)) => true,
+            None => message.text.contains(&self.search),
+        }
+    }
+
+    fn save_to_file(&self) {
+        let text: String = self
+            .messages
+            .iter()
+            .map(|message| message.format() + "\n")
+            .collect();
+        tokio::spawn(async move {
+            let file = AsyncFileDialog::new()
+                .add_filter("text", &["txt"])
+                .set_file_name("messages.txt")
+                .save_file()
+                .await;
+            if let Some(fi) = file {
+                if let Err(e) = tokio::fs::write(fi.path(), text).await {
+                    log::error!("Failed to save messages: {}", e);
+                }
+            }
+        });
+    }
+
+    fn show_toolbar(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal_wrapped(|ui| {
+            for (kind, name) in MESSAGE_KINDS {
+                let mut shown = self.shown_kinds.contains(&kind);
+                if ui.checkbox(&mut shown, name).changed() {
+                    if shown {
+                        self.shown_kinds.push(kind);
+                    } else {
+                        self.shown_kinds.retain(|k| *k != kind);
+                    }
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Search");
+            let search_changed = ui.text_edit_singleline(&mut self.search).changed();
+            let regex_changed = ui.checkbox(&mut self.use_regex, "Regex").changed();
+            if search_changed || regex_changed {
+                self.search_regex = self.use_regex.then(|| Regex::new(&self.search));
+            }
+        });
+        if let Some(Err(e)) = &self.search_regex {
+            ui.colored_label(ui.visuals().error_fg_color, e.to_string());
+        }
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut self.pause_scroll, "Pause scroll");
+            if ui.button("Save to file").clicked() {
+                self.save_to_file();
+            }
+        });
     }
 
     pub fn show_window(&mut self, ctx: &egui::Context) {
@@ -38,18 +207,20 @@ impl MessageWindow {
                 .with_inner_size([400.0, 480.0]),
             |ctx, _class| {
                 egui::CentralPanel::default().show(ctx, |ui| {
-                    ui.label(format!("message: {}", self.messages.len()));
+                    self.show_toolbar(ui);
+                    let rows: Vec<&Message> =
+                        self.messages.iter().filter(|m| self.matches(m)).collect();
+                    ui.label(format!("message: {} / {}", rows.len(), self.messages.len()));
                     ui.separator();
 
                     let text_style = egui::TextStyle::Body;
                     let row_height = ui.text_style_height(&text_style);
                     egui::ScrollArea::vertical()
-                        .stick_to_bottom(true)
+                        .stick_to_bottom(!self.pause_scroll)
                         .auto_shrink(false)
-                        .show_rows(ui, row_height, self.messages.len(), |ui, row_range| {
+                        .show_rows(ui, row_height, rows.len(), |ui, row_range| {
                             for row in row_range {
-                                let text = &self.messages[row];
-                                ui.label(text);
+                                ui.label(rows[row].format());
                             }
                         });
 
@@ -1,58 +1,69 @@
 use std::time;
 
+use crate::emulator::{
+    protocol::{EmulatorEvent, HostCommand},
+    TerminationReason, CLOCK_FREQUENCY,
+};
+
 use super::Simulator;
 
 impl Simulator {
-    pub fn parse_message(&mut self, message: String) {
-        if message.starts_with("stdout:") {
-            self.terminal.push(&message.replacen("stdout:", "", 1));
-            return;
+    pub fn parse_message(&mut self, message: Vec<u8>) {
+        match EmulatorEvent::decode(&message) {
+            Ok(event) => self.handle_event(event),
+            Err(e) => {
+                log::warn!("Failed to decode emulator message: {}", e);
+                self.message_window
+                    .push_messages(&[format!("decode error: {}", e).into_bytes()]);
+            }
         }
+    }
 
-        let list: Vec<&str> = message.split(':').collect();
-        match list[0] {
-            "ioport" => self.parse_ioport(list),
-            "ready" => {
+    fn handle_event(&mut self, event: EmulatorEvent) {
+        match event {
+            EmulatorEvent::Stdout(output) => self.terminal.push(&output),
+            EmulatorEvent::IoPort { port, value, state } => match port {
+                // 7SegLED | LED
+                0x4 | 0xb => self.io_port.write(port, value, state),
+                _ => (),
+            },
+            EmulatorEvent::Ready => {
                 if let Some(emulator) = &self.emulator {
                     self.send_initial_ioport(emulator);
+                    self.send_speed_limit(emulator);
 
-                    emulator.send_message("cmd:start");
+                    emulator.send_message(HostCommand::Start);
                     self.sync_timing = time::Instant::now();
                 }
             }
-            "sync" => {
-                if list.len() != 2 {
-                    return;
-                }
-                if let Ok(state) = list[1].parse::<usize>() {
-                    self.emulator_state = state;
-                } else {
-                    return;
-                }
+            EmulatorEvent::Sync { state } => {
+                // Measure from the last known state, which is not a whole sync
+                // interval after a pause.
+                let emulated_secs =
+                    state.saturating_sub(self.emulator_state) as f64 / CLOCK_FREQUENCY as f64;
+                self.emulator_state = state;
                 let duration = self.sync_timing.elapsed();
                 self.sync_timing = time::Instant::now();
-                self.speed = 0.1f64 / duration.as_secs_f64();
+                if self.paused {
+                    return;
+                }
+                self.speed = emulated_secs / duration.as_secs_f64();
                 self.ui_states.speed_buf.push(self.speed);
             }
-            _ => (),
-        }
-    }
-
-    fn parse_ioport(&mut self, list: Vec<&str>) {
-        if list.len() != 4 {
-            return;
-        }
-
-        if let Ok(port) = u8::from_str_radix(&list[1], 16) {
-            if let Ok(value) = u8::from_str_radix(&list[2], 16) {
-                if let Ok(state) = list[3].parse::<usize>() {
-                    match port {
-                        // 7SegLED | LED
-                        0x4 | 0xb => self.io_port.write(port, value, state),
-                        _ => (),
-                    }
-                }
+            EmulatorEvent::Paused { state } => {
+                self.emulator_state = state;
+                self.paused = true;
+            }
+            EmulatorEvent::Exit { code, state } => {
+                self.emulator_state = state;
+                self.termination = Some(TerminationReason::Exited(code));
+            }
+            EmulatorEvent::Crash { state, reason } => {
+                self.emulator_state = state;
+                self.termination = Some(TerminationReason::Crashed(reason));
             }
+            // The handshake is handled by `Emulator` before any message is popped.
+            EmulatorEvent::Hello { .. } | EmulatorEvent::Unknown(_) => (),
         }
     }
 }
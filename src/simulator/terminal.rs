@@ -1,51 +1,439 @@
-use eframe::egui;
+use decoder::{Decoded, OutputDecoder, OutputEncoding, OUTPUT_ENCODINGS};
+use eframe::egui::{self, text::LayoutJob, Color32, FontFamily, FontId, TextFormat};
+use output::OutputBuffer;
+use rfd::AsyncFileDialog;
+use screen::{Cell, Screen, Style, DEFAULT_MAX_LINES, SCREEN_ROWS};
+
+mod decoder;
+mod output;
+mod screen;
+
+const DEFAULT_FONT_SIZE: f32 = 12.0;
+/// Raw output kept per line of scrollback, so that decoding it again in
+/// another encoding restores the scrollback unless lines are longer.
+const BYTES_PER_LINE: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TerminalView {
+    Text,
+    Hex,
+}
 
 pub struct Terminal {
-    lines: Vec<String>,
+    output: OutputBuffer,
+    /// `output` decoded and interpreted.
+    screen: Screen,
+    view: TerminalView,
+    show_timestamps: bool,
+    decoder: OutputDecoder,
+    font: FontId,
     should_clear: bool,
+    input: String,
+    raw_input: bool,
+    pending_input: Vec<String>,
+    find: String,
+    /// Line number of the current find result, see
+    /// `Screen::first_line_number`.
+    found_row: Option<usize>,
+    /// Set to scroll to a line number on the next frame.
+    scroll_to_row: Option<usize>,
+    find_failed: bool,
 }
 
 impl Terminal {
     pub fn new() -> Self {
-        Self {
-            lines: vec![String::new()],
+        let mut terminal = Self {
+            output: OutputBuffer::new(),
+            screen: Screen::new(),
+            view: TerminalView::Text,
+            show_timestamps: false,
+            decoder: OutputDecoder::new(OutputEncoding::Utf8),
+            font: FontId::new(DEFAULT_FONT_SIZE, FontFamily::Monospace),
             should_clear: true,
-        }
+            input: String::new(),
+            raw_input: false,
+            pending_input: Vec::new(),
+            find: String::new(),
+            found_row: None,
+            scroll_to_row: None,
+            find_failed: false,
+        };
+        terminal.set_scrollback(DEFAULT_MAX_LINES);
+        terminal
     }
 
-    pub fn push(&mut self, string: &String) {
-        for c in string.chars() {
-            match c {
-                '\r' => continue,
-                '\n' => self.lines.push(String::new()),
-                _ => {
-                    let i = self.lines.len() - 1;
-                    self.lines[i].push(c);
-                }
+    fn set_scrollback(&mut self, max_lines: usize) {
+        self.screen.set_max_lines(max_lines);
+        self.output
+            .set_max_bytes(self.screen.max_lines() * BYTES_PER_LINE);
+    }
+
+    /// Adds output of the program.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.output.push(bytes);
+        self.decode(bytes);
+    }
+
+    fn decode(&mut self, bytes: &[u8]) {
+        for decoded in self.decoder.decode(bytes) {
+            match decoded {
+                Decoded::Text(text) => self.screen.push(&text),
+                Decoded::Invalid(bytes) => self.screen.push_invalid(&bytes),
             }
         }
     }
 
     pub fn clear(&mut self) {
         if self.should_clear {
-            self.lines.clear();
-            self.lines.push(String::new());
+            self.output.clear();
+            self.screen.clear();
+            self.found_row = None;
         }
+        self.decoder = OutputDecoder::new(self.decoder.encoding());
     }
 
-    pub fn show(&mut self, ui: &mut egui::Ui) {
+    /// Decodes the whole output again, in `encoding`.
+    fn set_encoding(&mut self, encoding: OutputEncoding) {
+        self.decoder = OutputDecoder::new(encoding);
+        self.screen.clear();
+        self.found_row = None;
+        let (front, back) = self.output.bytes().as_slices();
+        let bytes = [front, back].concat();
+        self.decode(&bytes);
+    }
+
+    /// The output as shown by the current view.
+    fn view_text(&self) -> String {
+        match self.view {
+            TerminalView::Text => self.screen.text(),
+            TerminalView::Hex => self.output.hex_dump(self.show_timestamps),
+        }
+    }
+
+    /// Returns the text typed since the last call, to be sent to stdin.
+    pub fn take_input(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.pending_input)
+    }
+
+    /// Finds the next (or previous) line containing `find`, wrapping around.
+    fn find_next(&mut self, forward: bool) {
+        let lines = self.screen.lines();
+        if self.find.is_empty() || lines.is_empty() {
+            self.found_row = None;
+            return;
+        }
+        let len = lines.len();
+        let first = self.screen.first_line_number();
+        // The result may have been dropped from the scrollback since.
+        let start = match self.found_row.and_then(|n| n.checked_sub(first)) {
+            Some(row) if forward => row + 1,
+            Some(row) => row + len - 1,
+            None if forward => 0,
+            None => len - 1,
+        };
+        self.found_row = (0..len)
+            .map(|i| {
+                if forward {
+                    (start + i) % len
+                } else {
+                    (start + len - i) % len
+                }
+            })
+            .find(|row| Screen::line_text(&lines[*row]).contains(&self.find))
+            .map(|row| first + row);
+        self.scroll_to_row = self.found_row;
+        self.find_failed = self.found_row.is_none();
+    }
+
+    /// Text of the current find result, unless it was dropped from the
+    /// scrollback.
+    fn found_line(&self) -> Option<String> {
+        let row = self
+            .found_row?
+            .checked_sub(self.screen.first_line_number())?;
+        self.screen
+            .lines()
+            .get(row)
+            .map(|line| Screen::line_text(line))
+    }
+
+    fn save_output(&self) {
+        let text = self.view_text();
+        tokio::spawn(async move {
+            let file = AsyncFileDialog::new()
+                .add_filter("text", &["txt"])
+                .set_file_name("output.txt")
+                .save_file()
+                .await;
+            if let Some(fi) = file {
+                if let Err(e) = tokio::fs::write(fi.path(), text).await {
+                    log::error!("Failed to save output: {}", e);
+                }
+            }
+        });
+    }
+
+    fn show_toolbar(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut self.should_clear, "Clear on start");
+            ui.checkbox(&mut self.raw_input, "Raw input")
+                .on_hover_text("Send every key stroke immediately");
+            ui.label("Scrollback");
+            let mut max_lines = self.screen.max_lines();
+            let response = ui.add(
+                egui::DragValue::new(&mut max_lines)
+                    .range(SCREEN_ROWS..=1_000_000)
+                    .suffix(" lines"),
+            );
+            let response = response.on_hover_text(format!(
+                "Keeps {} bytes of output per line, which are decoded again when the encoding changes",
+                BYTES_PER_LINE
+            ));
+            if response.changed() {
+                self.set_scrollback(max_lines);
+            }
+            let mut encoding = self.decoder.encoding();
+            egui::ComboBox::from_label("Encoding")
+                .selected_text(encoding.name())
+                .show_ui(ui, |ui| {
+                    for e in OUTPUT_ENCODINGS {
+                        ui.selectable_value(&mut encoding, e, e.name());
+                    }
+                });
+            if encoding != self.decoder.encoding() {
+                self.set_encoding(encoding);
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.selectable_value(&mut self.view, TerminalView::Text, "Text");
+            ui.selectable_value(&mut self.view, TerminalView::Hex, "Hex");
+            ui.add_enabled(
+                self.view == TerminalView::Hex,
+                egui::Checkbox::new(&mut self.show_timestamps, "Timestamps"),
+            );
+            ui.separator();
+            ui.label("Font");
+            egui::ComboBox::from_id_salt("terminal_font")
+                .selected_text(self.font.family.to_string())
+                .show_ui(ui, |ui| {
+                    for family in ui.ctx().fonts(|f| f.families()) {
+                        let text = family.to_string();
+                        ui.selectable_value(&mut self.font.family, family, text);
+                    }
+                });
+            ui.add(
+                egui::DragValue::new(&mut self.font.size)
+                    .range(6.0..=48.0)
+                    .suffix(" pt"),
+            );
+        });
+        ui.horizontal(|ui| {
+            if ui.button("Copy all").clicked() {
+                ui.ctx().copy_text(self.view_text());
+            }
+            if ui.button("Save output as...").clicked() {
+                self.save_output();
+            }
+            if self.view == TerminalView::Text {
+                self.show_find(ui);
+            }
+        });
+    }
+
+    fn show_find(&mut self, ui: &mut egui::Ui) {
+        ui.label("Find");
+        let response = ui.text_edit_singleline(&mut self.find);
+        if response.changed() {
+            self.found_row = None;
+            self.find_failed = false;
+        }
+        let enter_pressed = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+        if ui.button("Prev").clicked() {
+            self.find_next(false);
+        }
+        if ui.button("Next").clicked() || enter_pressed {
+            self.find_next(true);
+        }
+        if enter_pressed {
+            response.request_focus();
+        }
+        let found_line = self.found_line();
+        let copy = ui
+            .add_enabled(found_line.is_some(), egui::Button::new("Copy line"))
+            .on_hover_text("Copy the line of the current result");
+        if let Some(line) = found_line.filter(|_| copy.clicked()) {
+            ui.ctx().copy_text(line);
+        }
+        if self.find_failed {
+            ui.weak("Not found");
+        }
+    }
+
+    pub fn show(&mut self, ui: &mut egui::Ui, input_enabled: bool) {
         ui.strong("Terminal (stdout)");
-        ui.checkbox(&mut self.should_clear, "Clear on start");
-        let text_style = egui::TextStyle::Body;
-        let row_height = ui.text_style_height(&text_style);
-        egui::ScrollArea::vertical()
-            .stick_to_bottom(true)
-            .auto_shrink(false)
-            .show_rows(ui, row_height, self.lines.len(), |ui, row_range| {
-                for row in row_range {
-                    let text = &self.lines[row];
-                    ui.label(text);
+        self.show_toolbar(ui);
+        ui.add_enabled_ui(input_enabled, |ui| {
+            self.show_input(ui);
+        });
+        ui.scope(|ui| {
+            ui.spacing_mut().item_spacing.y = 0.0;
+            let row_height = ui.fonts(|f| f.row_height(&self.font));
+            let mut scroll_area = egui::ScrollArea::vertical()
+                .stick_to_bottom(true)
+                .auto_shrink(false);
+            let first = self.screen.first_line_number();
+            if let Some(row) = self.scroll_to_row.take().and_then(|n| n.checked_sub(first)) {
+                // Leave a few lines of context above the result.
+                scroll_area =
+                    scroll_area.vertical_scroll_offset(row.saturating_sub(3) as f32 * row_height);
+            }
+            match self.view {
+                TerminalView::Text => self.show_text_rows(ui, scroll_area, row_height),
+                TerminalView::Hex => self.show_hex_rows(ui, scroll_area, row_height),
+            }
+        });
+    }
+
+    fn show_text_rows(&self, ui: &mut egui::Ui, scroll_area: egui::ScrollArea, row_height: f32) {
+        let lines = self.screen.lines();
+        let first = self.screen.first_line_number();
+        scroll_area.show_rows(ui, row_height, lines.len(), |ui, row_range| {
+            for row in row_range {
+                let find = (!self.find.is_empty()).then_some(self.find.as_str());
+                let is_found_row = self.found_row == Some(first + row);
+                let job = self.layout_line(ui, &lines[row], find, is_found_row);
+                ui.add(egui::Label::new(job).extend());
+            }
+        });
+    }
+
+    fn show_hex_rows(&self, ui: &mut egui::Ui, scroll_area: egui::ScrollArea, row_height: f32) {
+        let format = self.text_format(ui, Style::default());
+        scroll_area.show_rows(ui, row_height, self.output.row_count(), |ui, row_range| {
+            for row in row_range {
+                let mut job = LayoutJob::default();
+                job.append(
+                    &self.output.hex_row(row, self.show_timestamps),
+                    0.0,
+                    format.clone(),
+                );
+                ui.add(egui::Label::new(job).extend());
+            }
+        });
+    }
+
+    /// Lays out `line` with one section per run of equally styled cells,
+    /// highlighting occurrences of `find`.
+    fn layout_line(
+        &self,
+        ui: &egui::Ui,
+        line: &[Cell],
+        find: Option<&str>,
+        is_found_row: bool,
+    ) -> LayoutJob {
+        let mut job = LayoutJob::default();
+        if line.is_empty() {
+            // Keeps the row height of empty lines.
+            job.append("", 0.0, self.text_format(ui, Style::default()));
+        }
+
+        let mut highlighted = vec![false; line.len()];
+        if let Some(find) = find {
+            let text = Screen::line_text(line);
+            for (byte_start, found) in text.match_indices(find) {
+                let start = text[..byte_start].chars().count();
+                let len = found.chars().count();
+                highlighted[start..start + len].fill(true);
+            }
+        }
+        let highlight = ui.visuals().selection.bg_fill;
+        let highlight = if is_found_row {
+            highlight
+        } else {
+            highlight.gamma_multiply(0.5)
+        };
+
+        let mut start = 0;
+        while start < line.len() {
+            let run = (line[start].style, highlighted[start]);
+            let end = (start..line.len())
+                .find(|i| (line[*i].style, highlighted[*i]) != run)
+                .unwrap_or(line.len());
+            let text: String = line[start..end].iter().map(|cell| cell.ch).collect();
+            let mut format = self.text_format(ui, run.0);
+            if run.1 {
+                format.background = highlight;
+            }
+            job.append(&text, 0.0, format);
+            start = end;
+        }
+        job
+    }
+
+    fn text_format(&self, ui: &egui::Ui, style: Style) -> TextFormat {
+        let visuals = ui.visuals();
+        let default_fg = if style.bold {
+            visuals.strong_text_color()
+        } else {
+            visuals.text_color()
+        };
+        let mut fg = style.fg.unwrap_or(default_fg);
+        let mut bg = style.bg.unwrap_or(Color32::TRANSPARENT);
+        if style.inverse {
+            (fg, bg) = (style.bg.unwrap_or(visuals.extreme_bg_color), fg);
+        }
+        if style.invalid {
+            fg = visuals.error_fg_color;
+        }
+        if style.dim {
+            fg = fg.gamma_multiply(0.6);
+        }
+        TextFormat {
+            font_id: self.font.clone(),
+            color: fg,
+            background: bg,
+            italics: style.italic,
+            underline: if style.underline {
+                egui::Stroke::new(1.0, fg)
+            } else {
+                egui::Stroke::NONE
+            },
+            ..Default::default()
+        }
+    }
+
+    fn show_input(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("stdin");
+            let send_clicked = !self.raw_input && ui.button("Send").clicked();
+            let response = ui.add_sized(
+                egui::Vec2::new(ui.available_width(), 0f32),
+                egui::TextEdit::singleline(&mut self.input),
+            );
+            let enter_pressed =
+                response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+
+            if self.raw_input {
+                if response.has_focus()
+                    && self.input.is_empty()
+                    && ui.input(|i| i.key_pressed(egui::Key::Backspace))
+                {
+                    self.pending_input.push("\x08".to_string());
+                }
+                if !self.input.is_empty() {
+                    self.pending_input.push(std::mem::take(&mut self.input));
                 }
-            });
+                if enter_pressed {
+                    self.pending_input.push("\n".to_string());
+                }
+            } else if enter_pressed || send_clicked {
+                self.pending_input
+                    .push(std::mem::take(&mut self.input) + "\n");
+            }
+
+            if enter_pressed {
+                response.request_focus();
+            }
+        });
     }
 }
@@ -0,0 +1,145 @@
+//! Decoding of the program output in the encoding selected by the user.
+
+use encoding_rs::{Decoder, DecoderResult, Encoding, EUC_JP, SHIFT_JIS, UTF_8};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputEncoding {
+    Utf8,
+    ShiftJis,
+    EucJp,
+    Latin1,
+}
+
+pub const OUTPUT_ENCODINGS: [OutputEncoding; 4] = [
+    OutputEncoding::Utf8,
+    OutputEncoding::ShiftJis,
+    OutputEncoding::EucJp,
+    OutputEncoding::Latin1,
+];
+
+impl OutputEncoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputEncoding::Utf8 => "UTF-8",
+            OutputEncoding::ShiftJis => "Shift_JIS",
+            OutputEncoding::EucJp => "EUC-JP",
+            OutputEncoding::Latin1 => "Latin-1",
+        }
+    }
+
+    /// `None` for Latin-1, which encoding_rs only has as windows-1252.
+    fn encoding(&self) -> Option<&'static Encoding> {
+        match self {
+            OutputEncoding::Utf8 => Some(UTF_8),
+            OutputEncoding::ShiftJis => Some(SHIFT_JIS),
+            OutputEncoding::EucJp => Some(EUC_JP),
+            OutputEncoding::Latin1 => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Decoded {
+    Text(String),
+    /// A sequence that is not valid in the encoding.
+    Invalid(Vec<u8>),
+}
+
+pub struct OutputDecoder {
+    encoding: OutputEncoding,
+    decoder: Option<Decoder>,
+}
+
+impl OutputDecoder {
+    pub fn new(encoding: OutputEncoding) -> Self {
+        Self {
+            encoding,
+            decoder: encoding
+                .encoding()
+                .map(|e| e.new_decoder_without_bom_handling()),
+        }
+    }
+
+    pub fn encoding(&self) -> OutputEncoding {
+        self.encoding
+    }
+
+    /// Decodes `bytes`. A sequence cut off at the end is completed by the
+    /// bytes of the next call.
+    pub fn decode(&mut self, bytes: &[u8]) -> Vec<Decoded> {
+        let Some(decoder) = self.decoder.as_mut() else {
+            return vec![Decoded::Text(bytes.iter().map(|b| *b as char).collect())];
+        };
+
+        let mut decoded = Vec::new();
+        let mut text = String::new();
+        let mut src = bytes;
+        loop {
+            let max_len = decoder.max_utf8_buffer_length_without_replacement(src.len());
+            text.reserve(max_len.unwrap_or(src.len() * 3 + 16));
+            let (result, read) =
+                decoder.decode_to_string_without_replacement(src, &mut text, false);
+            match result {
+                DecoderResult::InputEmpty => break,
+                DecoderResult::OutputFull => (),
+                DecoderResult::Malformed(len, extra) => {
+                    // Bytes of the sequence from an earlier call are lost.
+                    let end = read - extra as usize;
+                    let start = end.saturating_sub(len as usize);
+                    if !text.is_empty() {
+                        decoded.push(Decoded::Text(std::mem::take(&mut text)));
+                    }
+                    decoded.push(Decoded::Invalid(src[start..end].to_vec()));
+                }
+            }
+            src = &src[read..];
+        }
+        if !text.is_empty() {
+            decoded.push(Decoded::Text(text));
+        }
+        decoded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_sequences_split_across_calls() {
+        let mut decoder = OutputDecoder::new(OutputEncoding::ShiftJis);
+        // "日本" in Shift_JIS.
+        assert_eq!(
+            decoder.decode(b"\x93\xfa\x96"),
+            [Decoded::Text("日".to_string())]
+        );
+        assert_eq!(decoder.decode(b"\x7b"), [Decoded::Text("本".to_string())]);
+
+        let mut decoder = OutputDecoder::new(OutputEncoding::EucJp);
+        assert_eq!(
+            decoder.decode(b"\xc6\xfc\xcb\xdc"),
+            [Decoded::Text("日本".to_string())]
+        );
+    }
+
+    #[test]
+    fn keeps_invalid_sequences() {
+        let mut decoder = OutputDecoder::new(OutputEncoding::Utf8);
+        assert_eq!(
+            decoder.decode(b"a\xffb\x93\xfa"),
+            [
+                Decoded::Text("a".to_string()),
+                Decoded::Invalid(vec![0xff]),
+                Decoded::Text("b".to_string()),
+                Decoded::Invalid(vec![0x93]),
+                Decoded::Invalid(vec![0xfa]),
+            ]
+        );
+
+        let mut decoder = OutputDecoder::new(OutputEncoding::Latin1);
+        assert_eq!(
+            decoder.decode(b"\x93\xe9"),
+            [Decoded::Text("\u{93}é".to_string())]
+        );
+    }
+}
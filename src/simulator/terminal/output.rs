@@ -0,0 +1,179 @@
+//! The raw output of the program, which both views of the terminal show.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+pub const BYTES_PER_ROW: usize = 16;
+const DEFAULT_MAX_BYTES: usize = 1 << 20;
+
+pub struct OutputBuffer {
+    bytes: VecDeque<u8>,
+    /// Offset of the first byte of `bytes` since the buffer was cleared.
+    start: usize,
+    /// Offset of the first byte and time of each push, oldest first.
+    pushes: VecDeque<(usize, Duration)>,
+    created: Instant,
+    /// Older output is dropped beyond this, a row at a time.
+    max_bytes: usize,
+}
+
+impl OutputBuffer {
+    pub fn new() -> Self {
+        Self {
+            bytes: VecDeque::new(),
+            start: 0,
+            pushes: VecDeque::new(),
+            created: Instant::now(),
+            max_bytes: DEFAULT_MAX_BYTES,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self {
+            max_bytes: self.max_bytes,
+            ..Self::new()
+        };
+    }
+
+    /// Sets how many bytes are kept, at least a row.
+    pub fn set_max_bytes(&mut self, max_bytes: usize) {
+        self.max_bytes = max_bytes.max(BYTES_PER_ROW);
+        self.trim();
+    }
+
+    pub fn bytes(&self) -> &VecDeque<u8> {
+        &self.bytes
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.push_at(bytes, self.created.elapsed());
+    }
+
+    fn push_at(&mut self, bytes: &[u8], time: Duration) {
+        if bytes.is_empty() {
+            return;
+        }
+        self.pushes.push_back((self.start + self.bytes.len(), time));
+        self.bytes.extend(bytes);
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        if self.bytes.len() <= self.max_bytes {
+            return;
+        }
+        // Whole rows, so that rows keep their offsets.
+        let excess = (self.bytes.len() - self.max_bytes).div_ceil(BYTES_PER_ROW) * BYTES_PER_ROW;
+        self.bytes.drain(..excess);
+        self.start += excess;
+        while self
+            .pushes
+            .get(1)
+            .is_some_and(|(offset, _)| *offset <= self.start)
+        {
+            self.pushes.pop_front();
+        }
+    }
+
+    pub fn row_count(&self) -> usize {
+        self.bytes.len().div_ceil(BYTES_PER_ROW)
+    }
+
+    /// Time of the push that the byte at `offset` came with.
+    fn time_at(&self, offset: usize) -> Duration {
+        let i = self.pushes.partition_point(|(start, _)| *start <= offset);
+        self.pushes
+            .get(i.saturating_sub(1))
+            .map_or(Duration::ZERO, |(_, time)| *time)
+    }
+
+    /// Formats `row` as offset, hex and ASCII columns, optionally preceded
+    /// by the time its first byte was received.
+    pub fn hex_row(&self, row: usize, timestamp: bool) -> String {
+        let start = row * BYTES_PER_ROW;
+        let end = (start + BYTES_PER_ROW).min(self.bytes.len());
+        let bytes: Vec<u8> = self.bytes.range(start..end).copied().collect();
+
+        let mut line = String::new();
+        if timestamp {
+            let time = self.time_at(self.start + start);
+            line.push_str(&format!("{:>9.3}  ", time.as_secs_f64()));
+        }
+        line.push_str(&format!("{:08x} ", self.start + start));
+        for i in 0..BYTES_PER_ROW {
+            if i % 8 == 0 {
+                line.push(' ');
+            }
+            match bytes.get(i) {
+                Some(b) => line.push_str(&format!("{:02x} ", b)),
+                None => line.push_str("   "),
+            }
+        }
+        line.push_str(" |");
+        line.extend(bytes.iter().map(|b| {
+            if b.is_ascii_graphic() || *b == b' ' {
+                *b as char
+            } else {
+                '.'
+            }
+        }));
+        line.push('|');
+        line
+    }
+
+    /// All rows, as `hex_row` formats them.
+    pub fn hex_dump(&self, timestamp: bool) -> String {
+        let rows: Vec<String> = (0..self.row_count())
+            .map(|row| self.hex_row(row, timestamp))
+            .collect();
+        rows.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_hex_rows() {
+        let mut output = OutputBuffer::new();
+        output.push_at(
+            b"Hello, H8!\r\n\x00\x01\x7f\xff",
+            Duration::from_millis(1500),
+        );
+        output.push_at(b"ok", Duration::from_millis(2250));
+
+        assert_eq!(output.row_count(), 2);
+        assert_eq!(
+            output.hex_row(0, false),
+            "00000000  48 65 6c 6c 6f 2c 20 48  38 21 0d 0a 00 01 7f ff  |Hello, H8!......|"
+        );
+        assert_eq!(
+            output.hex_row(1, true),
+            "    2.250  00000010  6f 6b                                             |ok|"
+        );
+    }
+
+    #[test]
+    fn drops_whole_rows_beyond_limit() {
+        let mut output = OutputBuffer::new();
+        output.push_at(&[0; DEFAULT_MAX_BYTES], Duration::from_secs(1));
+        output.push_at(&[1; 20], Duration::from_secs(2));
+
+        assert_eq!(output.bytes().len(), DEFAULT_MAX_BYTES - 12);
+        assert!(output.hex_row(0, true).starts_with("    1.000  00000020 "));
+        let last = output.row_count() - 1;
+        assert!(output
+            .hex_row(last, true)
+            .starts_with("    2.000  00100010 "));
+
+        output.set_max_bytes(40);
+        assert_eq!(output.bytes().len(), 36);
+        assert!(output.hex_row(0, true).starts_with("    1.000  000ffff0 "));
+        output.clear();
+        output.push(&[2; 50]);
+        assert_eq!(output.bytes().len(), 34);
+    }
+}
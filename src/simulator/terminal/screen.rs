@@ -0,0 +1,524 @@
+//! A small VT100 interpreter for the output of the program.
+//!
+//! Cursor addressing works on the last `SCREEN_ROWS` lines, everything above
+//! them is scrollback that can no longer be changed.
+
+use eframe::egui::Color32;
+use std::collections::VecDeque;
+
+pub const SCREEN_ROWS: usize = 24;
+/// Longer lines wrap, and the cursor cannot move beyond it.
+pub const SCREEN_COLS: usize = 1024;
+/// Lines kept by default, including the screen.
+pub const DEFAULT_MAX_LINES: usize = 10_000;
+const TAB_WIDTH: usize = 8;
+
+/// The xterm colours for SGR 30-37 and 90-97.
+const PALETTE: [Color32; 16] = [
+    Color32::from_rgb(0, 0, 0),
+    Color32::from_rgb(205, 0, 0),
+    Color32::from_rgb(0, 205, 0),
+    Color32::from_rgb(205, 205, 0),
+    Color32::from_rgb(0, 0, 238),
+    Color32::from_rgb(205, 0, 205),
+    Color32::from_rgb(0, 205, 205),
+    Color32::from_rgb(229, 229, 229),
+    Color32::from_rgb(127, 127, 127),
+    Color32::from_rgb(255, 0, 0),
+    Color32::from_rgb(0, 255, 0),
+    Color32::from_rgb(255, 255, 0),
+    Color32::from_rgb(92, 92, 255),
+    Color32::from_rgb(255, 0, 255),
+    Color32::from_rgb(0, 255, 255),
+    Color32::from_rgb(255, 255, 255),
+];
+
+/// Colour `index` of the xterm 256 colour palette.
+fn indexed_color(index: u8) -> Color32 {
+    match index {
+        0..=15 => PALETTE[index as usize],
+        16..=231 => {
+            let level = |v: u8| if v == 0 { 0 } else { 55 + v * 40 };
+            let i = index - 16;
+            Color32::from_rgb(level(i / 36), level(i / 6 % 6), level(i % 6))
+        }
+        _ => {
+            let gray = 8 + (index - 232) * 10;
+            Color32::from_rgb(gray, gray, gray)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Style {
+    /// `None` for the default colour of the theme.
+    pub fg: Option<Color32>,
+    pub bg: Option<Color32>,
+    pub bold: bool,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub inverse: bool,
+    /// Bytes that could not be decoded, shown as `\xNN`.
+    pub invalid: bool,
+}
+
+impl Style {
+    /// Applies the parameters of an SGR sequence.
+    fn apply_sgr(&mut self, params: &[usize]) {
+        if params.is_empty() {
+            *self = Style::default();
+            return;
+        }
+        let mut params = params.iter().copied();
+        while let Some(param) = params.next() {
+            match param {
+                0 => *self = Style::default(),
+                1 => self.bold = true,
+                2 => self.dim = true,
+                3 => self.italic = true,
+                4 => self.underline = true,
+                7 => self.inverse = true,
+                22 => {
+                    self.bold = false;
+                    self.dim = false;
+                }
+                23 => self.italic = false,
+                24 => self.underline = false,
+                27 => self.inverse = false,
+                30..=37 => self.fg = Some(PALETTE[param - 30]),
+                38 => self.fg = Self::extended_color(&mut params),
+                39 => self.fg = None,
+                40..=47 => self.bg = Some(PALETTE[param - 40]),
+                48 => self.bg = Self::extended_color(&mut params),
+                49 => self.bg = None,
+                90..=97 => self.fg = Some(PALETTE[param - 90 + 8]),
+                100..=107 => self.bg = Some(PALETTE[param - 100 + 8]),
+                _ => (),
+            }
+        }
+    }
+
+    /// Reads the rest of `38;5;n` or `38;2;r;g;b`.
+    fn extended_color(params: &mut impl Iterator<Item = usize>) -> Option<Color32> {
+        let mut component = || params.next().map(|v| v.min(255) as u8);
+        match component() {
+            Some(5) => component().map(indexed_color),
+            Some(2) => Some(Color32::from_rgb(component()?, component()?, component()?)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub ch: char,
+    pub style: Style,
+}
+
+impl Cell {
+    const BLANK: Cell = Cell {
+        ch: ' ',
+        style: Style {
+            fg: None,
+            bg: None,
+            bold: false,
+            dim: false,
+            italic: false,
+            underline: false,
+            inverse: false,
+            invalid: false,
+        },
+    };
+}
+
+enum ParserState {
+    Ground,
+    Escape,
+    /// Character set designations, whose final byte is ignored.
+    Charset,
+    /// Parameters and intermediate bytes read so far.
+    Csi(String),
+}
+
+pub struct Screen {
+    lines: VecDeque<Vec<Cell>>,
+    /// The oldest lines are dropped beyond this.
+    max_lines: usize,
+    /// Lines dropped so far, so lines can be referred to by a number that
+    /// does not change when older lines are dropped.
+    dropped_lines: usize,
+    row: usize,
+    col: usize,
+    saved_cursor: (usize, usize),
+    style: Style,
+    state: ParserState,
+}
+
+impl Screen {
+    pub fn new() -> Self {
+        Self {
+            lines: VecDeque::from([Vec::new()]),
+            max_lines: DEFAULT_MAX_LINES,
+            dropped_lines: 0,
+            row: 0,
+            col: 0,
+            saved_cursor: (0, 0),
+            style: Style::default(),
+            state: ParserState::Ground,
+        }
+    }
+
+    pub fn lines(&self) -> &VecDeque<Vec<Cell>> {
+        &self.lines
+    }
+
+    /// Number of the first of `lines`. Adding it to an index into `lines`
+    /// gives a line number that stays valid while lines are dropped.
+    pub fn first_line_number(&self) -> usize {
+        self.dropped_lines
+    }
+
+    pub fn line_text(line: &[Cell]) -> String {
+        line.iter().map(|cell| cell.ch).collect()
+    }
+
+    /// All lines as plain text.
+    pub fn text(&self) -> String {
+        let lines: Vec<String> = self
+            .lines
+            .iter()
+            .map(|line| Self::line_text(line))
+            .collect();
+        lines.join("\n")
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self {
+            max_lines: self.max_lines,
+            dropped_lines: self.dropped_lines + self.lines.len(),
+            ..Self::new()
+        };
+    }
+
+    pub fn max_lines(&self) -> usize {
+        self.max_lines
+    }
+
+    /// Sets how many lines are kept, at least the screen.
+    pub fn set_max_lines(&mut self, max_lines: usize) {
+        self.max_lines = max_lines.max(SCREEN_ROWS);
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        let excess = self.lines.len().saturating_sub(self.max_lines);
+        self.lines.drain(..excess);
+        self.dropped_lines += excess;
+        self.row -= excess;
+    }
+
+    fn screen_top(&self) -> usize {
+        self.lines.len().saturating_sub(SCREEN_ROWS)
+    }
+
+    pub fn push(&mut self, string: &str) {
+        for c in string.chars() {
+            self.feed(c);
+        }
+    }
+
+    /// Shows `bytes` that could not be decoded as escapes in their own style.
+    pub fn push_invalid(&mut self, bytes: &[u8]) {
+        let style = self.style;
+        self.style.invalid = true;
+        for b in bytes {
+            format!("\\x{:02x}", b).chars().for_each(|c| self.put(c));
+        }
+        self.style = style;
+    }
+
+    fn feed(&mut self, c: char) {
+        match &mut self.state {
+            ParserState::Ground => self.feed_ground(c),
+            ParserState::Escape => {
+                self.state = match c {
+                    '[' => ParserState::Csi(String::new()),
+                    '(' | ')' => ParserState::Charset,
+                    '7' => {
+                        self.save_cursor();
+                        ParserState::Ground
+                    }
+                    '8' => {
+                        self.restore_cursor();
+                        ParserState::Ground
+                    }
+                    'c' => {
+                        self.clear();
+                        ParserState::Ground
+                    }
+                    _ => ParserState::Ground,
+                };
+            }
+            ParserState::Charset => self.state = ParserState::Ground,
+            ParserState::Csi(params) => match c {
+                '\x40'..='\x7e' => {
+                    let params = std::mem::take(params);
+                    self.state = ParserState::Ground;
+                    self.execute_csi(&params, c);
+                }
+                '\x20'..='\x3f' => params.push(c),
+                // Anything else aborts the sequence.
+                _ => {
+                    self.state = ParserState::Ground;
+                    self.feed_ground(c);
+                }
+            },
+        }
+    }
+
+    fn feed_ground(&mut self, c: char) {
+        match c {
+            '\x1b' => self.state = ParserState::Escape,
+            '\r' => self.col = 0,
+            '\n' => self.line_feed(),
+            '\x08' => self.col = self.col.saturating_sub(1),
+            '\t' => self.move_to_col((self.col / TAB_WIDTH + 1) * TAB_WIDTH),
+            c if c.is_control() => (),
+            c => self.put(c),
+        }
+    }
+
+    fn line_feed(&mut self) {
+        self.row += 1;
+        self.col = 0;
+        if self.row == self.lines.len() {
+            self.lines.push_back(Vec::new());
+            self.trim();
+        }
+    }
+
+    fn put(&mut self, c: char) {
+        if self.col >= SCREEN_COLS {
+            self.line_feed();
+        }
+        let line = &mut self.lines[self.row];
+        if line.len() <= self.col {
+            line.resize(self.col, Cell::BLANK);
+            line.push(Cell {
+                ch: c,
+                style: self.style,
+            });
+        } else {
+            line[self.col] = Cell {
+                ch: c,
+                style: self.style,
+            };
+        }
+        self.col += 1;
+    }
+
+    /// Moves the cursor to `row` of the screen, adding lines if the screen
+    /// is not full yet.
+    fn move_to_row(&mut self, row: usize) {
+        let top = self.screen_top();
+        self.row = top + row.min(SCREEN_ROWS - 1);
+        if self.row >= self.lines.len() {
+            self.lines.resize(self.row + 1, Vec::new());
+        }
+    }
+
+    fn move_to_col(&mut self, col: usize) {
+        self.col = col.min(SCREEN_COLS - 1);
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved_cursor = (self.row - self.screen_top(), self.col);
+    }
+
+    fn restore_cursor(&mut self) {
+        let (row, col) = self.saved_cursor;
+        self.move_to_row(row);
+        self.col = col;
+    }
+
+    fn execute_csi(&mut self, params: &str, command: char) {
+        // Private sequences such as `?25l` only change modes we do not have.
+        if params.starts_with(|c: char| matches!(c, '<'..='?')) {
+            return;
+        }
+        let values: Vec<usize> = params
+            .split(';')
+            .filter(|_| !params.is_empty())
+            .map(|p| p.parse().unwrap_or(0))
+            .collect();
+        let param = |i: usize| values.get(i).copied().unwrap_or(0);
+        let count = param(0).max(1);
+        let screen_row = self.row - self.screen_top();
+
+        match command {
+            'A' => self.move_to_row(screen_row.saturating_sub(count)),
+            'B' => {
+                let last = self.lines.len() - 1 - self.screen_top();
+                self.move_to_row(screen_row.saturating_add(count).min(last));
+            }
+            'C' => self.move_to_col(self.col.saturating_add(count)),
+            'D' => self.col = self.col.saturating_sub(count),
+            'E' => {
+                self.move_to_row(screen_row.saturating_add(count));
+                self.col = 0;
+            }
+            'F' => {
+                self.move_to_row(screen_row.saturating_sub(count));
+                self.col = 0;
+            }
+            'G' => self.move_to_col(count - 1),
+            'H' | 'f' => {
+                self.move_to_row(param(0).max(1) - 1);
+                self.move_to_col(param(1).max(1) - 1);
+            }
+            'd' => self.move_to_row(count - 1),
+            'J' => self.erase_display(param(0)),
+            'K' => self.erase_line(param(0)),
+            'm' => self.style.apply_sgr(&values),
+            's' => self.save_cursor(),
+            'u' => self.restore_cursor(),
+            _ => (),
+        }
+    }
+
+    fn erase_line(&mut self, mode: usize) {
+        let col = self.col;
+        let line = &mut self.lines[self.row];
+        match mode {
+            0 => line.truncate(col),
+            1 => line
+                .iter_mut()
+                .take(col + 1)
+                .for_each(|cell| *cell = Cell::BLANK),
+            _ => line.clear(),
+        }
+    }
+
+    fn erase_display(&mut self, mode: usize) {
+        let top = self.screen_top();
+        match mode {
+            0 => {
+                self.erase_line(0);
+                self.lines.range_mut(self.row + 1..).for_each(Vec::clear);
+            }
+            1 => {
+                self.lines.range_mut(top..self.row).for_each(Vec::clear);
+                self.erase_line(1);
+            }
+            2 => self.lines.range_mut(top..).for_each(Vec::clear),
+            // Also erases the scrollback.
+            _ => {
+                let (row, col) = (self.row - top, self.col);
+                self.dropped_lines += self.lines.len();
+                self.lines = VecDeque::from([Vec::new()]);
+                self.move_to_row(row);
+                self.col = col;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(screen: &Screen) -> Vec<String> {
+        screen
+            .lines()
+            .iter()
+            .map(|line| Screen::line_text(line))
+            .collect()
+    }
+
+    #[test]
+    fn moves_cursor_and_erases() {
+        let mut screen = Screen::new();
+        screen.push("hello\r\nworld\n");
+        assert_eq!(text(&screen), ["hello", "world", ""]);
+
+        screen.push("abc\x08\x08X\rY");
+        assert_eq!(text(&screen)[2], "YXc");
+
+        screen.push("\x1b[1;3HZ\x1b[K\x1b[2;2H\x1b[1K");
+        assert_eq!(text(&screen), ["heZ", "  rld", "YXc"]);
+
+        screen.push("\x1b[2J\x1b[Hnew");
+        assert_eq!(text(&screen), ["new", "", ""]);
+    }
+
+    #[test]
+    fn cursor_addresses_last_screen_rows() {
+        let mut screen = Screen::new();
+        for i in 0..30 {
+            screen.push(&format!("{}\n", i));
+        }
+        screen.push("\x1b[1;1Htop\x1b[3B\x1b[2Cx");
+        let text = text(&screen);
+        assert_eq!(text.len(), 31);
+        assert_eq!(text[7], "top");
+        assert_eq!(text[10], "10   x");
+    }
+
+    #[test]
+    fn applies_sgr_across_pushes() {
+        let mut screen = Screen::new();
+        screen.push("a\x1b[1;3");
+        screen.push("1mb\x1b[38;5;21;48;2;1;2;3mc\x1b[0md");
+        let styles: Vec<Style> = screen.lines()[0].iter().map(|cell| cell.style).collect();
+        assert_eq!(styles[0], Style::default());
+        assert_eq!(
+            styles[1],
+            Style {
+                fg: Some(PALETTE[1]),
+                bold: true,
+                ..Style::default()
+            }
+        );
+        assert_eq!(styles[2].fg, Some(Color32::from_rgb(0, 0, 255)));
+        assert_eq!(styles[2].bg, Some(Color32::from_rgb(1, 2, 3)));
+        assert_eq!(styles[3], Style::default());
+    }
+
+    #[test]
+    fn bounds_cursor_column() {
+        let mut screen = Screen::new();
+        screen.push("\x1b[18446744073709551615C\x1b[99999999999Ca");
+        screen.push("\x1b[1;99999999999Hb\r\x1b[99999999999Gc");
+        screen.push(&"\t".repeat(SCREEN_COLS));
+        let text = text(&screen);
+        assert_eq!(text.len(), 1);
+        assert_eq!(text[0].len(), SCREEN_COLS);
+        assert!(text[0].ends_with('c'));
+
+        screen.push("\n");
+        screen.push(&"x".repeat(SCREEN_COLS + 1));
+        let text = self::text(&screen);
+        assert_eq!(text.len(), 3);
+        assert_eq!(text[1].len(), SCREEN_COLS);
+        assert_eq!(text[2], "x");
+    }
+
+    #[test]
+    fn drops_lines_beyond_limit() {
+        let mut screen = Screen::new();
+        screen.set_max_lines(SCREEN_ROWS);
+        for i in 0..30 {
+            screen.push(&format!("{}\n", i));
+        }
+        screen.push("\x1b[1;1Htop");
+        let text = text(&screen);
+        assert_eq!(text.len(), SCREEN_ROWS);
+        assert_eq!(text[0], "top");
+        assert_eq!(text[1], "8");
+        assert_eq!(screen.first_line_number(), 7);
+
+        screen.push("\x1bc");
+        assert_eq!(screen.first_line_number(), 7 + SCREEN_ROWS);
+    }
+}
@@ -0,0 +1,376 @@
+use super::ioport::IoPort;
+use crate::{board, emulator::CLOCK_FREQUENCY};
+use eframe::egui::{self, pos2, Color32, Pos2, Rect, Stroke};
+
+const LABEL_WIDTH: f32 = 48.0;
+const AXIS_HEIGHT: f32 = 20.0;
+const ROW_HEIGHT: f32 = 18.0;
+const ROW_COUNT: usize = board::PORTS.len() * 8;
+/// Minimum distance between time axis ticks.
+const TICK_SPACING: f32 = 80.0;
+/// How close to an edge, in pixels, a cursor snaps to it.
+const SNAP_DISTANCE: f32 = 6.0;
+const MIN_VIEW_STATES: f64 = 20.0;
+const DEFAULT_VIEW_STATES: f64 = CLOCK_FREQUENCY as f64 / 10.0;
+const PORT_NAMES: [&str; 3] = ["P4", "P5", "PB"];
+const CURSOR_NAMES: [&str; 2] = ["A", "B"];
+const CURSOR_COLORS: [Color32; 2] = [Color32::from_rgb(255, 190, 0), Color32::LIGHT_BLUE];
+
+fn states_to_us(states: f64) -> f64 {
+    states * 1_000_000.0 / CLOCK_FREQUENCY as f64
+}
+
+/// Formats `us` with just enough decimals for `resolution`, also in µs, so
+/// float noise like `0.6000000000000001` is not shown.
+fn format_us(us: f64, resolution: f64) -> String {
+    let (value, resolution, unit) = if us.abs() >= 1000.0 {
+        (us / 1000.0, resolution / 1000.0, "ms")
+    } else {
+        (us, resolution, "µs")
+    };
+    let decimals = (-resolution.log10() - 1e-6).ceil().max(0.0) as usize;
+    format!("{:.*} {}", decimals, value, unit)
+}
+
+/// Bits are shown from the most significant one down, port by port.
+fn row_of(port_index: usize, bit: usize) -> usize {
+    port_index * 8 + (7 - bit)
+}
+
+/// Maps states to x coordinates of the plot area.
+struct View {
+    start: f64,
+    len: f64,
+    rect: Rect,
+}
+
+impl View {
+    fn x(&self, state: f64) -> f32 {
+        self.rect.left() + ((state - self.start) / self.len) as f32 * self.rect.width()
+    }
+
+    fn state(&self, x: f32) -> f64 {
+        self.start + ((x - self.rect.left()) / self.rect.width()) as f64 * self.len
+    }
+
+    fn end(&self) -> f64 {
+        self.start + self.len
+    }
+}
+
+pub struct TimelineWindow {
+    /// First visible state.
+    view_start: f64,
+    /// Number of visible states.
+    view_len: f64,
+    /// Keep the latest state at the right edge.
+    follow: bool,
+    cursors: [Option<usize>; 2],
+    pub is_opened_timeline_window: bool,
+}
+
+impl TimelineWindow {
+    pub fn new() -> Self {
+        Self {
+            view_start: 0.0,
+            view_len: DEFAULT_VIEW_STATES,
+            follow: true,
+            cursors: [None; 2],
+            is_opened_timeline_window: false,
+        }
+    }
+
+    fn show_toolbar(&mut self, ui: &mut egui::Ui, latest_state: usize) {
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut self.follow, "Follow");
+            if ui.button("Zoom to fit").clicked() {
+                self.follow = false;
+                self.view_start = 0.0;
+                self.view_len = (latest_state as f64).max(MIN_VIEW_STATES);
+            }
+            if ui.button("Clear cursors").clicked() {
+                self.cursors = [None; 2];
+            }
+        });
+        ui.horizontal(|ui| {
+            for (i, cursor) in self.cursors.iter().enumerate() {
+                let text = match cursor {
+                    Some(state) => format!(
+                        "{}: {}",
+                        CURSOR_NAMES[i],
+                        format_us(states_to_us(*state as f64), states_to_us(1.0))
+                    ),
+                    None => format!("{}: -", CURSOR_NAMES[i]),
+                };
+                ui.colored_label(CURSOR_COLORS[i], text);
+            }
+            if let [Some(a), Some(b)] = self.cursors {
+                ui.label(format!(
+                    "Δ: {}",
+                    format_us(states_to_us(b as f64 - a as f64), states_to_us(1.0))
+                ));
+            }
+        });
+        ui.weak("Drag to scroll, wheel to zoom, left/right click to place cursors A/B.");
+    }
+
+    fn handle_input(&mut self, ui: &egui::Ui, response: &egui::Response, plot: Rect) {
+        if response.dragged() {
+            self.follow = false;
+            self.view_start -= (response.drag_delta().x / plot.width()) as f64 * self.view_len;
+        }
+        if let Some(pointer) = response.hover_pos() {
+            let scroll = ui.input(|i| i.smooth_scroll_delta.y);
+            if scroll != 0.0 {
+                let ratio = ((pointer.x - plot.left()) / plot.width()).clamp(0.0, 1.0) as f64;
+                let anchor = self.view_start + ratio * self.view_len;
+                self.view_len = (self.view_len * (-scroll as f64 * 0.005).exp())
+                    .clamp(MIN_VIEW_STATES, usize::MAX as f64);
+                self.view_start = anchor - ratio * self.view_len;
+            }
+        }
+        self.view_start = self.view_start.max(0.0);
+    }
+
+    fn draw_axis(&self, painter: &egui::Painter, view: &View, axis: Rect, ui: &egui::Ui) {
+        let min_step = states_to_us(view.len) * (TICK_SPACING / view.rect.width()) as f64;
+        let magnitude = 10f64.powf(min_step.log10().floor());
+        let step = [1.0, 2.0, 5.0, 10.0]
+            .into_iter()
+            .map(|m| m * magnitude)
+            .find(|step| *step >= min_step)
+            .unwrap_or(magnitude * 10.0);
+
+        let grid = Stroke::new(1.0, ui.visuals().widgets.noninteractive.bg_stroke.color);
+        // Multiplying instead of accumulating keeps the error from growing.
+        let first = (states_to_us(view.start) / step).ceil() * step;
+        for i in 0.. {
+            let tick = first + i as f64 * step;
+            if tick > states_to_us(view.end()) {
+                break;
+            }
+            let x = view.x(tick * CLOCK_FREQUENCY as f64 / 1_000_000.0);
+            painter.vline(x, view.rect.y_range(), grid);
+            painter.text(
+                pos2(x, axis.center().y),
+                egui::Align2::CENTER_CENTER,
+                format_us(tick, step),
+                egui::FontId::proportional(11.0),
+                ui.visuals().text_color(),
+            );
+        }
+    }
+
+    /// Draws every bit of every port and returns the x coordinates and
+    /// states of the visible edges of each row.
+    fn draw_signals(
+        &self,
+        painter: &egui::Painter,
+        view: &View,
+        io_port: &IoPort,
+        latest_state: usize,
+        stroke: Stroke,
+    ) -> Vec<Vec<(f32, usize)>> {
+        let history = io_port.history();
+        let mut values = io_port.history_start();
+        let first = history.partition_point(|w| (w.state as f64) < view.start);
+        for write in history.range(..first) {
+            values[board::port_index(write.port).unwrap()] = write.value;
+        }
+
+        let level_y = |row: usize, level: bool| {
+            let top = view.rect.top() + row as f32 * ROW_HEIGHT;
+            if level {
+                top + 3.0
+            } else {
+                top + ROW_HEIGHT - 3.0
+            }
+        };
+        let level = |values: &[u8; 3], row: usize| (values[row / 8] >> (7 - row % 8)) & 1 == 1;
+
+        let mut levels: [bool; ROW_COUNT] = std::array::from_fn(|row| level(&values, row));
+        let mut segment_start = [view.rect.left(); ROW_COUNT];
+        let mut edges = vec![Vec::new(); ROW_COUNT];
+        for write in history.range(first..) {
+            if write.state as f64 > view.end() {
+                break;
+            }
+            let index = board::port_index(write.port).unwrap();
+            let changed = values[index] ^ write.value;
+            values[index] = write.value;
+            let x = view.x(write.state as f64);
+            for bit in (0..8).filter(|bit| (changed >> bit) & 1 == 1) {
+                let row = row_of(index, bit);
+                // Edges closer than a pixel would only be drawn over each other.
+                if edges[row].last().is_none_or(|(last, _)| x - last >= 1.0) {
+                    painter.hline(segment_start[row]..=x, level_y(row, levels[row]), stroke);
+                    painter.vline(x, level_y(row, false)..=level_y(row, true), stroke);
+                    edges[row].push((x, write.state));
+                    segment_start[row] = x;
+                }
+                levels[row] = level(&values, row);
+            }
+        }
+
+        let end = view.x(latest_state as f64).min(view.rect.right());
+        for row in 0..ROW_COUNT {
+            if segment_start[row] < end {
+                painter.hline(segment_start[row]..=end, level_y(row, levels[row]), stroke);
+            }
+        }
+        edges
+    }
+
+    fn place_cursor(
+        &mut self,
+        cursor: usize,
+        pointer: Pos2,
+        view: &View,
+        edges: &[Vec<(f32, usize)>],
+    ) {
+        let row = ((pointer.y - view.rect.top()) / ROW_HEIGHT) as usize;
+        let nearest = edges.get(row).and_then(|row| {
+            row.iter()
+                .map(|(x, state)| ((x - pointer.x).abs(), *state))
+                .filter(|(distance, _)| *distance <= SNAP_DISTANCE)
+                .min_by(|a, b| a.0.total_cmp(&b.0))
+        });
+        self.cursors[cursor] = Some(match nearest {
+            Some((_, state)) => state,
+            None => view.state(pointer.x).max(0.0) as usize,
+        });
+    }
+
+    fn show_timeline(&mut self, ui: &mut egui::Ui, io_port: &IoPort, latest_state: usize) {
+        let size = egui::vec2(
+            ui.available_width(),
+            AXIS_HEIGHT + ROW_HEIGHT * ROW_COUNT as f32,
+        );
+        let (response, painter) = ui.allocate_painter(size, egui::Sense::click_and_drag());
+        let rect = response.rect;
+        let plot = Rect::from_min_max(
+            pos2(rect.left() + LABEL_WIDTH, rect.top() + AXIS_HEIGHT),
+            rect.max,
+        );
+        if plot.width() <= 0.0 {
+            return;
+        }
+
+        self.handle_input(ui, &response, plot);
+        if self.follow {
+            self.view_start = (latest_state as f64 - self.view_len).max(0.0);
+        }
+        let view = View {
+            start: self.view_start,
+            len: self.view_len,
+            rect: plot,
+        };
+
+        let visuals = ui.visuals();
+        for row in 0..ROW_COUNT {
+            let top = plot.top() + row as f32 * ROW_HEIGHT;
+            if (row / 8) % 2 == 1 {
+                painter.rect_filled(
+                    Rect::from_min_max(
+                        pos2(rect.left(), top),
+                        pos2(rect.right(), top + ROW_HEIGHT),
+                    ),
+                    0.0,
+                    visuals.faint_bg_color,
+                );
+            }
+            painter.text(
+                pos2(rect.left() + 4.0, top + ROW_HEIGHT / 2.0),
+                egui::Align2::LEFT_CENTER,
+                format!("{}.{}", PORT_NAMES[row / 8], 7 - row % 8),
+                egui::FontId::monospace(12.0),
+                visuals.text_color(),
+            );
+        }
+        let axis = Rect::from_min_max(
+            pos2(plot.left(), rect.top()),
+            pos2(plot.right(), plot.top()),
+        );
+        self.draw_axis(&painter, &view, axis, ui);
+
+        let stroke = Stroke::new(1.5, visuals.strong_text_color());
+        let edges = self.draw_signals(
+            &painter.with_clip_rect(plot),
+            &view,
+            io_port,
+            latest_state,
+            stroke,
+        );
+
+        if let Some(pointer) = response.interact_pointer_pos() {
+            if response.clicked() {
+                self.place_cursor(0, pointer, &view, &edges);
+            } else if response.secondary_clicked() {
+                self.place_cursor(1, pointer, &view, &edges);
+            }
+        }
+        for (i, cursor) in self.cursors.iter().enumerate() {
+            if let Some(state) = cursor {
+                let x = view.x(*state as f64);
+                if plot.x_range().contains(x) {
+                    painter.vline(x, plot.y_range(), Stroke::new(1.0, CURSOR_COLORS[i]));
+                    painter.text(
+                        pos2(x + 2.0, plot.top()),
+                        egui::Align2::LEFT_TOP,
+                        CURSOR_NAMES[i],
+                        egui::FontId::proportional(11.0),
+                        CURSOR_COLORS[i],
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn show_window(&mut self, ctx: &egui::Context, io_port: &IoPort, latest_state: usize) {
+        if !self.is_opened_timeline_window {
+            return;
+        }
+        ctx.show_viewport_immediate(
+            egui::ViewportId::from_hash_of("timeline_window"),
+            egui::ViewportBuilder::default()
+                .with_title("Port Timeline")
+                .with_inner_size([720.0, 560.0]),
+            |ctx, _class| {
+                egui::CentralPanel::default().show(ctx, |ui| {
+                    self.show_toolbar(ui, latest_state);
+                    ui.separator();
+                    self.show_timeline(ui, io_port, latest_state);
+
+                    if ctx.input(|i| i.viewport().close_requested()) {
+                        self.is_opened_timeline_window = false;
+                    }
+                });
+            },
+        )
+    }
+
+    pub fn show_open_close_button(&mut self, ui: &mut egui::Ui) {
+        let text = if self.is_opened_timeline_window {
+            "Close timeline"
+        } else {
+            "Open timeline"
+        };
+        if ui.button(text).clicked() {
+            self.is_opened_timeline_window = !self.is_opened_timeline_window
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_with_resolution() {
+        assert_eq!(format_us(0.1 + 0.2 + 0.3, 0.2), "0.6 µs");
+        assert_eq!(format_us(0.6000000000000001, states_to_us(1.0)), "0.60 µs");
+        assert_eq!(format_us(2.0 * 3.0, 2.0), "6 µs");
+        assert_eq!(format_us(1500.0, 500.0), "1.5 ms");
+        assert_eq!(format_us(20_000.0, 10_000.0), "20 ms");
+    }
+}
@@ -1,12 +1,27 @@
-use super::Simulator;
+use super::{ioport, Simulator};
+use crate::board;
+use crate::emulator::{
+    protocol::{Capability, HostCommand},
+    TerminationReason, CLOCK_FREQUENCY, DEFAULT_EMULATOR_PORT, EMULATOR_HOST,
+};
 use eframe::egui::{self, Color32, FontId, TextFormat, Vec2};
 use egui_extras::Column;
 use rfd::AsyncFileDialog;
 use std::{
     cell::RefCell,
+    path::PathBuf,
     sync::{Arc, Mutex},
 };
 
+const SPEED_LIMITS: [Option<f64>; 4] = [Some(0.01), Some(0.1), Some(1.0), None];
+
+fn speed_limit_text(limit: Option<f64>) -> String {
+    match limit {
+        Some(speed) => format!("x{}", speed),
+        None => "max".to_string(),
+    }
+}
+
 pub struct SimulatorUiStates {
     pub elf_path: Arc<Mutex<String>>,
     pub elf_args: String,
@@ -14,6 +29,17 @@ pub struct SimulatorUiStates {
     pub push_switches: RefCell<[bool; 5]>,
     pub speed: f64,
     pub speed_buf: Vec<f64>,
+    pub use_fixed_port: bool,
+    pub fixed_port: u16,
+    pub attach_addr: String,
+    pub step_states: usize,
+    pub run_ms: usize,
+    pub speed_limit: Option<f64>,
+    pub record_session: bool,
+    pub session_path: String,
+    /// Set by the file dialog of the replay button.
+    pub replay_path: Arc<Mutex<Option<PathBuf>>>,
+    pub history_limit: usize,
 }
 
 impl SimulatorUiStates {
@@ -25,6 +51,16 @@ impl SimulatorUiStates {
             push_switches: RefCell::new([false; 5]),
             speed: 0f64,
             speed_buf: Vec::new(),
+            use_fixed_port: false,
+            fixed_port: DEFAULT_EMULATOR_PORT,
+            attach_addr: format!("{}:{}", EMULATOR_HOST, DEFAULT_EMULATOR_PORT),
+            step_states: 1000,
+            run_ms: 100,
+            speed_limit: None,
+            record_session: false,
+            session_path: "session.jsonl".to_string(),
+            replay_path: Arc::new(Mutex::new(None)),
+            history_limit: ioport::DEFAULT_HISTORY_LIMIT,
         }
     }
 }
@@ -46,13 +82,51 @@ fn select_elf(elf_path: Arc<Mutex<String>>) {
     });
 }
 
+fn select_session(replay_path: Arc<Mutex<Option<PathBuf>>>) {
+    tokio::spawn(async move {
+        let file = AsyncFileDialog::new()
+            .add_filter("session", &["jsonl"])
+            .pick_file()
+            .await;
+        if let Some(fi) = file {
+            if let Ok(mut l) = replay_path.lock() {
+                *l = Some(fi.path().to_path_buf())
+            }
+        }
+    });
+}
+
 impl Simulator {
+    fn export_vcd(&self) {
+        let mut vcd = Vec::new();
+        if let Err(e) = self.io_port.export_vcd(&mut vcd) {
+            log::error!("Failed to export VCD: {}", e);
+            return;
+        }
+        tokio::spawn(async move {
+            let file = AsyncFileDialog::new()
+                .add_filter("vcd", &["vcd"])
+                .set_file_name("ports.vcd")
+                .save_file()
+                .await;
+            if let Some(fi) = file {
+                if let Err(e) = tokio::fs::write(fi.path(), vcd).await {
+                    log::error!("Failed to export VCD: {}", e);
+                }
+            }
+        });
+    }
+
     pub fn ui(&mut self, ui: &mut egui::Ui, ctx: &egui::Context) {
-        self.update();
+        self.update(ctx);
 
-        if ui.button("Select elf").clicked() {
-            select_elf(self.ui_states.elf_path.clone());
-        }
+        ui.horizontal(|ui| {
+            if ui.button("Select elf").clicked() {
+                select_elf(self.ui_states.elf_path.clone());
+            }
+            ui.checkbox(&mut self.elf_watcher.enabled, "Restart on change")
+                .on_hover_text("Execute the elf again when the file is rewritten");
+        });
 
         ui.add_sized(
             Vec2::new(ui.available_width(), 0f32),
@@ -72,28 +146,88 @@ impl Simulator {
 
         ui.add_space(4.0);
 
+        self.show_settings(ui);
+
+        ui.add_space(4.0);
+
         ui.horizontal_wrapped(|ui| {
             if self.emulator.is_none() {
                 ui.add_enabled_ui(self.emulator_exec_rx.is_none(), |ui| {
                     if ui.button("execute").clicked() {
                         self.execute_emulator(ctx);
                     }
+                    if ui.button("attach").clicked() {
+                        self.attach_emulator(ctx);
+                    }
+                    if ui.button("replay").clicked() {
+                        select_session(self.ui_states.replay_path.clone());
+                    }
                 });
             } else {
-                if ui.button("stop").clicked() {
-                    self.stop_emulator();
+                ui.add_enabled_ui(!self.is_stopping(), |ui| {
+                    if ui.button("stop").clicked() {
+                        self.stop_emulator();
+                    }
+                });
+                ui.add_enabled_ui(self.emulator_supports(Capability::Pause), |ui| {
+                    if self.paused {
+                        if ui.button("resume").clicked() {
+                            self.resume_emulator();
+                        }
+                    } else if ui.button("pause").clicked() {
+                        self.pause_emulator();
+                    }
+                });
+                if self.emulator.as_ref().is_some_and(|e| e.is_attached())
+                    && ui.button("detach").clicked()
+                {
+                    self.detach_emulator();
                 }
             }
             ui.with_layout(egui::Layout::right_to_left(egui::Align::TOP), |ui| {
                 self.message_window.show_open_close_button(ui);
+                self.emulator_log.show_open_close_button(ui);
+                self.timeline_window.show_open_close_button(ui);
+                if ui.button("Export VCD").clicked() {
+                    self.export_vcd();
+                }
             })
         });
 
-        if self.emulator.is_some() {
-            ui.label("Emulator is running.");
+        if self.paused && self.emulator_supports(Capability::Pause) {
+            self.show_step_controls(ui);
+        }
+
+        if let Some(emulator) = &self.emulator {
+            if self.is_stopping() {
+                ui.label("Emulator is stopping...");
+            } else if self.paused {
+                ui.label(format!(
+                    "Emulator is paused at state {}.",
+                    self.emulator_state
+                ));
+            } else if emulator.is_replay() {
+                ui.label("Replaying session.");
+            } else if emulator.is_attached() {
+                ui.label("Emulator is attached.");
+            } else {
+                ui.label("Emulator is running.");
+            }
+            if emulator.is_recording() {
+                ui.label(format!(
+                    "Recording session to {}",
+                    self.ui_states.session_path
+                ));
+            }
         } else {
             ui.label("Emulator is stopped.");
         }
+        if let Some(e) = &self.emulator_error {
+            ui.colored_label(ui.visuals().error_fg_color, e.to_string());
+        }
+        if self.emulator.is_none() {
+            self.show_run_summary(ui);
+        }
 
         if self.ui_states.speed == 0f64 {
             if let Some(speed) = self.ui_states.speed_buf.first() {
@@ -105,7 +239,11 @@ impl Simulator {
                 / self.ui_states.speed_buf.len() as f64;
             self.ui_states.speed_buf.clear();
         }
-        ui.label(format!("Speed: x{:.6}", self.ui_states.speed));
+        ui.add_enabled(
+            self.emulator.is_none() || self.emulator_supports(Capability::Sync),
+            egui::Label::new(format!("Speed: x{:.6}", self.ui_states.speed)),
+        );
+        self.show_speed_limit(ui);
 
         ui.separator();
 
@@ -117,9 +255,119 @@ impl Simulator {
 
         ui.separator();
 
-        self.terminal.show(ui);
+        self.terminal
+            .show(ui, self.emulator_supports(Capability::Stdin));
+        self.send_terminal_input();
 
         self.message_window.show_window(ctx);
+        self.emulator_log.show_window(ctx);
+        let latest_state = self.get_corrected_current_emulator_state();
+        self.timeline_window
+            .show_window(ctx, &self.io_port, latest_state);
+    }
+
+    fn show_run_summary(&self, ui: &mut egui::Ui) {
+        let Some(summary) = &self.run_summary else {
+            return;
+        };
+        let text = format!(
+            "{} at state {} ({:.6} s)",
+            summary.reason,
+            summary.state,
+            summary.elapsed_secs()
+        );
+        match summary.reason {
+            TerminationReason::Exited(0)
+            | TerminationReason::StoppedByUser
+            | TerminationReason::Detached => ui.label(text),
+            _ => ui.colored_label(ui.visuals().warn_fg_color, text),
+        };
+        if let Some(status) = summary.process_status {
+            ui.label(format!("Emulator process: {}", status));
+        }
+    }
+
+    fn show_speed_limit(&mut self, ui: &mut egui::Ui) {
+        let enabled = self.emulator.is_none() || self.emulator_supports(Capability::Speed);
+        let prev_limit = self.ui_states.speed_limit;
+        ui.add_enabled_ui(enabled, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Speed limit");
+                for limit in SPEED_LIMITS {
+                    ui.selectable_value(
+                        &mut self.ui_states.speed_limit,
+                        limit,
+                        speed_limit_text(limit),
+                    );
+                }
+            });
+        });
+        if self.ui_states.speed_limit != prev_limit {
+            self.ui_states.speed = 0f64;
+            self.ui_states.speed_buf.clear();
+            if let Some(emulator) = &self.emulator {
+                self.send_speed_limit(emulator);
+            }
+        }
+    }
+
+    fn show_step_controls(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal_wrapped(|ui| {
+            if ui.button("step").clicked() {
+                self.step_emulator(self.ui_states.step_states);
+            }
+            ui.add(
+                egui::DragValue::new(&mut self.ui_states.step_states)
+                    .range(1..=usize::MAX)
+                    .suffix(" states"),
+            );
+            ui.separator();
+            if ui.button("run for").clicked() {
+                self.step_emulator(self.ui_states.run_ms * (CLOCK_FREQUENCY / 1000));
+            }
+            ui.add(
+                egui::DragValue::new(&mut self.ui_states.run_ms)
+                    .range(1..=60_000)
+                    .suffix(" ms"),
+            );
+        });
+    }
+
+    fn show_settings(&mut self, ui: &mut egui::Ui) {
+        ui.collapsing("Settings", |ui| {
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut self.ui_states.use_fixed_port, "Fixed port");
+                ui.add_enabled(
+                    self.ui_states.use_fixed_port,
+                    egui::DragValue::new(&mut self.ui_states.fixed_port).range(1..=u16::MAX),
+                );
+            });
+            ui.horizontal(|ui| {
+                ui.label("Attach address");
+                ui.text_edit_singleline(&mut self.ui_states.attach_addr);
+            });
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut self.ui_states.record_session, "Record session to")
+                    .on_hover_text("Takes effect on the next execute or attach");
+                ui.add_enabled(
+                    self.ui_states.record_session,
+                    egui::TextEdit::singleline(&mut self.ui_states.session_path),
+                );
+            });
+            ui.horizontal(|ui| {
+                ui.label("Port history limit");
+                let response = ui.add(
+                    egui::DragValue::new(&mut self.ui_states.history_limit)
+                        .range(1000..=100_000_000)
+                        .suffix(" writes"),
+                );
+                if response.changed() {
+                    self.io_port.set_history_limit(self.ui_states.history_limit);
+                }
+            })
+            .response
+            .on_hover_text("Writes kept for the timeline and VCD export");
+        });
     }
 
     fn show_modules(&mut self, ui: &mut egui::Ui) {
@@ -128,9 +376,14 @@ impl Simulator {
             columns[0].add_space(4.0);
             self.show_digit_led(&mut columns[0]);
 
-            self.show_toggle_switches(&mut columns[1]);
-            columns[1].add_space(4.0);
-            self.show_push_switches(&mut columns[1]);
+            // Switches stay usable while stopped to set the initial state.
+            let switches_enabled =
+                self.emulator.is_none() || self.emulator_supports(Capability::IoPort);
+            columns[1].add_enabled_ui(switches_enabled, |ui| {
+                self.show_toggle_switches(ui);
+                ui.add_space(4.0);
+                self.show_push_switches(ui);
+            });
         });
     }
 
@@ -184,20 +437,9 @@ impl Simulator {
                 ..Default::default()
             };
 
-            self.io_port.filter_port4(
-                self.get_corrected_current_emulator_state()
-                    .saturating_sub(200_000 * 3),
-            );
-            let port4 = self.io_port.read_port4();
-
-            let mut diaplay_num: [Option<u8>; 4] = [None; 4];
-            for item in port4 {
-                for i in 0..4 {
-                    if (item.0 >> i) & 1 == 1 {
-                        diaplay_num[i] = Some(item.0 >> 4);
-                    }
-                }
-            }
+            let state = self.get_corrected_current_emulator_state();
+            self.io_port.filter_port4(state);
+            let diaplay_num = board::seven_seg_digits(self.io_port.read_port4(), state);
 
             let mut job = LayoutJob::default();
             for (i, num) in diaplay_num.iter().enumerate() {
@@ -231,26 +473,17 @@ impl Simulator {
         }
         if is_changed {
             let switches = self.ui_states.toggle_switches.borrow();
-            if switches[0] {
-                self.io_port
-                    .write(5, self.io_port.read(5).unwrap() | (1 << 2), 0); // TODO
-            } else {
-                self.io_port
-                    .write(5, self.io_port.read(5).unwrap() & !(1 << 2), 0); // TODO
-            }
-            if switches[1] {
-                self.io_port
-                    .write(5, self.io_port.read(5).unwrap() | (1 << 3), 0); // TODO
-            } else {
-                self.io_port
-                    .write(5, self.io_port.read(5).unwrap() & !(1 << 3), 0); // TODO
+            let mut port5 = self.io_port.read(board::SWITCH_PORT).unwrap();
+            for (i, on) in switches.iter().take(board::SWITCH_COUNT).enumerate() {
+                port5 = board::set_toggle_switch(port5, i, *on);
             }
+            let state = self.get_corrected_current_emulator_state();
+            self.io_port.write(board::SWITCH_PORT, port5, state);
             if let Some(emulator) = self.emulator.as_mut() {
-                emulator.send_message(format!(
-                    "ioport:{:x}:{:x}",
-                    0x5,
-                    self.io_port.read(5).unwrap()
-                ));
+                emulator.send_message(HostCommand::IoPort {
+                    port: 0x5,
+                    value: self.io_port.read(5).unwrap(),
+                });
             }
         }
     }
@@ -273,26 +506,17 @@ impl Simulator {
         }
         if is_changed {
             let switches = self.ui_states.push_switches.borrow();
-            if switches[0] {
-                self.io_port
-                    .write(5, self.io_port.read(5).unwrap() & !(1 << 0), 0); // TODO
-            } else {
-                self.io_port
-                    .write(5, self.io_port.read(5).unwrap() | (1 << 0), 0); // TODO
-            }
-            if switches[1] {
-                self.io_port
-                    .write(5, self.io_port.read(5).unwrap() & !(1 << 1), 0); // TODO
-            } else {
-                self.io_port
-                    .write(5, self.io_port.read(5).unwrap() | (1 << 1), 0); // TODO
+            let mut port5 = self.io_port.read(board::SWITCH_PORT).unwrap();
+            for (i, pressed) in switches.iter().take(board::SWITCH_COUNT).enumerate() {
+                port5 = board::set_push_switch(port5, i, *pressed);
             }
+            let state = self.get_corrected_current_emulator_state();
+            self.io_port.write(board::SWITCH_PORT, port5, state);
             if let Some(emulator) = self.emulator.as_mut() {
-                emulator.send_message(format!(
-                    "ioport:{:x}:{:x}",
-                    0x5,
-                    self.io_port.read(5).unwrap()
-                ));
+                emulator.send_message(HostCommand::IoPort {
+                    port: 0x5,
+                    value: self.io_port.read(5).unwrap(),
+                });
             }
         }
     }
@@ -0,0 +1,185 @@
+//! Value Change Dump export of port activity, for waveform viewers such as
+//! GTKWave.
+
+use crate::{
+    board::{self, PortWrite},
+    emulator::CLOCK_FREQUENCY,
+};
+use std::io::{self, Write};
+
+/// The VCD time unit. Timescales must be 1, 10 or 100 of a unit, so times
+/// are written in ns rather than in states.
+const NS_PER_STATE: usize = 1_000_000_000 / CLOCK_FREQUENCY;
+
+struct Signal {
+    name: &'static str,
+    port: u8,
+    /// Lowest bit of the signal within the port.
+    shift: u8,
+    width: u8,
+}
+
+const SIGNALS: &[Signal] = &[
+    Signal::new("port4", board::SEVEN_SEG_PORT, 0, 8),
+    Signal::new("port5", board::SWITCH_PORT, 0, 8),
+    Signal::new("portb", board::LED_PORT, 0, 8),
+    Signal::new("seg_select", board::SEVEN_SEG_PORT, 0, 4),
+    Signal::new("seg_digit", board::SEVEN_SEG_PORT, 4, 4),
+    Signal::new("push_sw0_n", board::SWITCH_PORT, 0, 1),
+    Signal::new("push_sw1_n", board::SWITCH_PORT, 1, 1),
+    Signal::new("toggle_sw0", board::SWITCH_PORT, 2, 1),
+    Signal::new("toggle_sw1", board::SWITCH_PORT, 3, 1),
+    Signal::new("led0_n", board::LED_PORT, 0, 1),
+    Signal::new("led1_n", board::LED_PORT, 1, 1),
+    Signal::new("led2_n", board::LED_PORT, 2, 1),
+    Signal::new("led3_n", board::LED_PORT, 3, 1),
+    Signal::new("led4_n", board::LED_PORT, 4, 1),
+    Signal::new("led5_n", board::LED_PORT, 5, 1),
+    Signal::new("led6_n", board::LED_PORT, 6, 1),
+    Signal::new("led7_n", board::LED_PORT, 7, 1),
+];
+
+impl Signal {
+    const fn new(name: &'static str, port: u8, shift: u8, width: u8) -> Self {
+        Signal {
+            name,
+            port,
+            shift,
+            width,
+        }
+    }
+
+    /// Short identifier of the signal in the value changes.
+    fn id(index: usize) -> char {
+        (b'!' + index as u8) as char
+    }
+
+    fn value(&self, port_value: u8) -> u8 {
+        (port_value >> self.shift) & ((1u16 << self.width) - 1) as u8
+    }
+
+    fn write_change(&self, out: &mut impl Write, id: char, value: u8) -> io::Result<()> {
+        if self.width == 1 {
+            writeln!(out, "{}{}", value, id)
+        } else {
+            writeln!(
+                out,
+                "b{:0width$b} {}",
+                value,
+                id,
+                width = self.width as usize
+            )
+        }
+    }
+}
+
+/// Writes `writes`, in state order, as a VCD file. Ports start out with the
+/// values in `initial`, indexed like `board::PORTS`.
+pub fn write_vcd<'a>(
+    out: &mut impl Write,
+    initial: [u8; 3],
+    writes: impl IntoIterator<Item = &'a PortWrite>,
+) -> io::Result<()> {
+    writeln!(out, "$comment H8 Practice Kit Simulator port activity $end")?;
+    writeln!(out, "$timescale 1 ns $end")?;
+    writeln!(out, "$scope module h8pks $end")?;
+    for (i, signal) in SIGNALS.iter().enumerate() {
+        let range = if signal.width == 1 {
+            String::new()
+        } else {
+            format!(" [{}:0]", signal.width - 1)
+        };
+        writeln!(
+            out,
+            "$var wire {} {} {}{} $end",
+            signal.width,
+            Signal::id(i),
+            signal.name,
+            range
+        )?;
+    }
+    writeln!(out, "$upscope $end")?;
+    writeln!(out, "$enddefinitions $end")?;
+
+    let mut ports = initial;
+    writeln!(out, "#0")?;
+    writeln!(out, "$dumpvars")?;
+    for (i, signal) in SIGNALS.iter().enumerate() {
+        let port_value = ports[board::port_index(signal.port).unwrap()];
+        signal.write_change(out, Signal::id(i), signal.value(port_value))?;
+    }
+    writeln!(out, "$end")?;
+
+    let mut time = 0;
+    for write in writes {
+        let Some(index) = board::port_index(write.port) else {
+            continue;
+        };
+        let previous = ports[index];
+        if previous == write.value {
+            continue;
+        }
+        ports[index] = write.value;
+
+        if write.state * NS_PER_STATE != time {
+            time = write.state * NS_PER_STATE;
+            writeln!(out, "#{}", time)?;
+        }
+        for (i, signal) in SIGNALS.iter().enumerate() {
+            if signal.port == write.port && signal.value(previous) != signal.value(write.value) {
+                signal.write_change(out, Signal::id(i), signal.value(write.value))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_changed_signals() {
+        let writes = [
+            PortWrite {
+                state: 0,
+                port: 0xb,
+                value: 0xff,
+            },
+            PortWrite {
+                state: 20,
+                port: 0xb,
+                value: 0xfe,
+            },
+            PortWrite {
+                state: 20,
+                port: 0x5,
+                value: 0x7,
+            },
+            PortWrite {
+                state: 40,
+                port: 0x4,
+                value: 0x31,
+            },
+            PortWrite {
+                state: 60,
+                port: 0x4,
+                value: 0x31,
+            },
+        ];
+        let mut out = Vec::new();
+        write_vcd(&mut out, [0x00, 0x03, 0xff], &writes).unwrap();
+        let vcd = String::from_utf8(out).unwrap();
+
+        assert!(vcd.contains("$var wire 8 ! port4 [7:0] $end\n"));
+        assert!(vcd.contains("$var wire 1 * led0_n $end\n"));
+        let changes = vcd.split("$dumpvars\n").nth(1).unwrap();
+        assert_eq!(
+            changes,
+            "b00000000 !\nb00000011 \"\nb11111111 #\nb0000 $\nb0000 %\n1&\n1'\n0(\n0)\n\
+             1*\n1+\n1,\n1-\n1.\n1/\n10\n11\n$end\n\
+             #1000\nb11111110 #\n0*\nb00000111 \"\n1(\n\
+             #2000\nb00110001 !\nb0001 $\nb0011 %\n"
+        );
+    }
+}
@@ -0,0 +1,47 @@
+//! Helpers shared by the integration tests.
+
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+
+pub const FAKE_EMULATOR: &str = env!("CARGO_BIN_EXE_fake_emulator");
+
+/// A file in the temp directory that is removed when dropped.
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Reserves a path unique to this test process without creating the file.
+    pub fn new(name: &str, extension: &str) -> Self {
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "h8pks-{}-{}.{}",
+            name,
+            std::process::id(),
+            extension
+        ));
+        Self { path }
+    }
+
+    /// Writes a fake emulator script, which is passed in place of an ELF path.
+    pub fn script(name: &str, script: &str) -> Self {
+        let file = Self::new(name, "txt");
+        std::fs::write(&file.path, script).unwrap();
+        file
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn path_string(&self) -> String {
+        self.path.to_str().unwrap().to_string()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,335 @@
+mod common;
+
+use common::{TempFile, FAKE_EMULATOR};
+use eframe::egui;
+use h8pks::emulator::{
+    find_free_port,
+    protocol::{Capability, EmulatorEvent, HostCommand, PROTOCOL_VERSION},
+    Emulator, EmulatorError, LogLine, LogStream,
+};
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn execute(name: &str, script: &str) -> Result<Emulator, EmulatorError> {
+    execute_recording(name, script, None).await
+}
+
+async fn execute_recording(
+    name: &str,
+    script: &str,
+    session_path: Option<&Path>,
+) -> Result<Emulator, EmulatorError> {
+    // The fake emulator reads its script before it starts listening.
+    let script = TempFile::script(name, script);
+    let (log_tx, _) = tokio::sync::mpsc::channel(64);
+    Emulator::execute_with_path(
+        PathBuf::from(FAKE_EMULATOR),
+        script.path_string(),
+        String::new(),
+        None,
+        session_path,
+        log_tx,
+        egui::Context::default(),
+    )
+    .await
+}
+
+/// Collects decoded events until the emulator finishes or `count` events
+/// have arrived.
+async fn collect_events(emulator: &mut Emulator, count: usize) -> Vec<EmulatorEvent> {
+    let start = Instant::now();
+    let mut events = Vec::new();
+    while events.len() < count && start.elapsed() < TIMEOUT {
+        let finished = emulator.is_finished();
+        for message in emulator.pop_messages() {
+            events.push(EmulatorEvent::decode(&message).unwrap());
+        }
+        if finished {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    events
+}
+
+#[tokio::test]
+async fn plays_back_scripted_messages() {
+    let mut emulator = execute(
+        "playback",
+        "ready\nsync:2000000\nioport:b:f0:2000100\nstdout:hello\\nworld\n",
+    )
+    .await
+    .unwrap();
+
+    let events = collect_events(&mut emulator, 4).await;
+    assert_eq!(
+        events,
+        vec![
+            EmulatorEvent::Ready,
+            EmulatorEvent::Sync { state: 2_000_000 },
+            EmulatorEvent::IoPort {
+                port: 0xb,
+                value: 0xf0,
+                state: 2_000_100
+            },
+            EmulatorEvent::Stdout(b"hello\nworld".to_vec()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn waits_for_host_commands() {
+    let mut emulator = execute(
+        "commands",
+        "ready\n@expect ioport:5:3\n@expect cmd:start\nstdout:started\n",
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        collect_events(&mut emulator, 1).await,
+        vec![EmulatorEvent::Ready]
+    );
+    emulator.send_message(HostCommand::IoPort { port: 5, value: 3 });
+    emulator.send_message(HostCommand::Start);
+    assert_eq!(
+        collect_events(&mut emulator, 1).await,
+        vec![EmulatorEvent::Stdout(b"started".to_vec())]
+    );
+}
+
+#[tokio::test]
+async fn escapes_stdin() {
+    let mut emulator = execute("stdin", "ready\n@expect stdin:C:\\\\tmp\\n\nstdout:ok\n")
+        .await
+        .unwrap();
+
+    emulator.send_message(HostCommand::Stdin("C:\\tmp\n".to_string()));
+    assert_eq!(
+        collect_events(&mut emulator, 2).await,
+        vec![EmulatorEvent::Ready, EmulatorEvent::Stdout(b"ok".to_vec())]
+    );
+}
+
+#[tokio::test]
+async fn sends_long_lines_intact() {
+    // Longer than the socket buffer, so that it is written in parts.
+    let text = "0123456789abcdef".repeat(1 << 18);
+    let mut emulator = execute(
+        "stdin-long",
+        &format!("ready\n@expect stdin:{}\nstdout:ok\n", text),
+    )
+    .await
+    .unwrap();
+
+    emulator.send_message(HostCommand::Stdin(text));
+    assert_eq!(
+        collect_events(&mut emulator, 2).await,
+        vec![EmulatorEvent::Ready, EmulatorEvent::Stdout(b"ok".to_vec())]
+    );
+}
+
+#[tokio::test]
+async fn negotiates_capabilities() {
+    let emulator = execute("capabilities", "@capabilities ioport,memory\nready\n")
+        .await
+        .unwrap();
+
+    assert!(emulator.supports(Capability::IoPort));
+    assert!(!emulator.supports(Capability::Sync));
+}
+
+#[tokio::test]
+async fn rejects_incompatible_version() {
+    let result = execute("version", &format!("@version {}\n", PROTOCOL_VERSION + 1)).await;
+
+    assert!(matches!(
+        result,
+        Err(EmulatorError::IncompatibleVersion { .. })
+    ));
+}
+
+#[tokio::test]
+async fn rejects_emulator_without_handshake() {
+    let result = execute("no-hello", "@no-hello\nready\n").await;
+
+    assert!(matches!(
+        result,
+        Err(EmulatorError::UnsupportedProtocol { ref line, .. }) if line == "ready"
+    ));
+}
+
+#[tokio::test]
+async fn reports_early_exit() {
+    let (log_tx, mut log_rx) = tokio::sync::mpsc::channel(64);
+    let result = Emulator::execute_with_path(
+        PathBuf::from(FAKE_EMULATOR),
+        "does-not-exist.txt".to_string(),
+        String::new(),
+        None,
+        None,
+        log_tx,
+        egui::Context::default(),
+    )
+    .await;
+
+    assert!(matches!(result, Err(EmulatorError::ProcessExited(_))));
+    let line = tokio::time::timeout(TIMEOUT, log_rx.recv()).await.unwrap();
+    assert!(matches!(
+        line,
+        Some(LogLine {
+            stream: LogStream::Stderr,
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+async fn captures_process_output() {
+    let script = TempFile::script("log", "@print starting\n@eprint crashed\n@exit 101\n");
+    let (log_tx, mut log_rx) = tokio::sync::mpsc::channel(64);
+    let mut emulator = Emulator::execute_with_path(
+        PathBuf::from(FAKE_EMULATOR),
+        script.path_string(),
+        String::new(),
+        None,
+        None,
+        log_tx,
+        egui::Context::default(),
+    )
+    .await
+    .unwrap();
+
+    collect_events(&mut emulator, 1).await;
+    drop(emulator);
+
+    let mut lines = Vec::new();
+    while let Ok(Some(line)) = tokio::time::timeout(TIMEOUT, log_rx.recv()).await {
+        lines.push(line);
+    }
+    lines.sort_by_key(|line| line.stream == LogStream::Stderr);
+    assert_eq!(
+        lines,
+        vec![
+            LogLine {
+                stream: LogStream::Stdout,
+                text: "starting".to_string()
+            },
+            LogLine {
+                stream: LogStream::Stderr,
+                text: "crashed".to_string()
+            },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn attaches_to_running_emulator() {
+    let script = TempFile::script("attach", "ready\n@expect cmd:stop\n");
+    let port = find_free_port().unwrap();
+    let mut process = tokio::process::Command::new(FAKE_EMULATOR)
+        .kill_on_drop(true)
+        .args(["--elf", &script.path_string(), "--port", &port.to_string()])
+        .spawn()
+        .unwrap();
+
+    let mut emulator = Emulator::attach(
+        format!("127.0.0.1:{}", port),
+        None,
+        egui::Context::default(),
+    )
+    .await
+    .unwrap();
+    assert!(emulator.is_attached());
+    assert_eq!(
+        collect_events(&mut emulator, 1).await,
+        vec![EmulatorEvent::Ready]
+    );
+
+    emulator.send_message(HostCommand::Stop);
+    let status = tokio::time::timeout(TIMEOUT, process.wait())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(status.success());
+}
+
+#[tokio::test]
+async fn shuts_down_on_stop() {
+    let emulator = execute("shutdown", "ready\n@expect cmd:stop\n@exit 3\n")
+        .await
+        .unwrap();
+
+    let status = emulator.shutdown(TIMEOUT).await.unwrap();
+    assert_eq!(status.code(), Some(3));
+}
+
+#[tokio::test]
+async fn kills_unresponsive_emulator_on_shutdown() {
+    let emulator = execute("shutdown-kill", "ready\n@sleep 60000\n")
+        .await
+        .unwrap();
+
+    let start = Instant::now();
+    let status = emulator.shutdown(Duration::from_millis(200)).await.unwrap();
+    assert!(!status.success());
+    assert!(start.elapsed() < TIMEOUT);
+}
+
+#[tokio::test]
+async fn records_and_replays_session() {
+    let session = TempFile::new("record", "jsonl");
+    let mut emulator = execute_recording(
+        "record",
+        "ready\n@expect cmd:start\nioport:b:f0:100\n@sleep 200\nstdout:done\\n\n",
+        Some(session.path()),
+    )
+    .await
+    .unwrap();
+    assert!(emulator.is_recording());
+
+    let mut recorded = collect_events(&mut emulator, 1).await;
+    assert_eq!(recorded, vec![EmulatorEvent::Ready]);
+    emulator.send_message(HostCommand::Start);
+    recorded.extend(collect_events(&mut emulator, 2).await);
+    drop(emulator);
+
+    let mut replay = Emulator::replay(session.path().to_path_buf(), egui::Context::default())
+        .await
+        .unwrap();
+    assert!(replay.is_replay());
+    assert!(!replay.is_attached());
+    let start = Instant::now();
+    let events = collect_events(&mut replay, 3).await;
+    assert_eq!(events.first(), Some(&EmulatorEvent::Ready));
+    assert_eq!(events, recorded);
+    assert!(start.elapsed() >= Duration::from_millis(200));
+}
+
+#[tokio::test]
+async fn stops_replay() {
+    let session = TempFile::new("replay-stop", "jsonl");
+    std::fs::write(
+        session.path(),
+        "{\"format\":\"h8pks-session\",\"version\":1,\"capabilities\":\"sync\"}\n\
+         {\"time\":0.0,\"dir\":\"in\",\"state\":0,\"line\":\"ready\"}\n\
+         {\"time\":60.0,\"dir\":\"in\",\"state\":0,\"line\":\"stdout:late\"}\n",
+    )
+    .unwrap();
+
+    let mut replay = Emulator::replay(session.path().to_path_buf(), egui::Context::default())
+        .await
+        .unwrap();
+    assert!(replay.supports(Capability::Sync));
+    assert_eq!(
+        collect_events(&mut replay, 1).await,
+        vec![EmulatorEvent::Ready]
+    );
+    replay.send_message(HostCommand::Stop);
+    assert_eq!(collect_events(&mut replay, 1).await, vec![]);
+    assert!(replay.is_finished());
+}
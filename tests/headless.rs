@@ -0,0 +1,50 @@
+mod common;
+
+use common::{TempFile, FAKE_EMULATOR};
+use h8pks::headless::{run_with_path, RunOptions, EXIT_CRASHED, EXIT_TIMEOUT};
+use std::{path::PathBuf, time::Duration};
+
+async fn run(name: &str, script: &str, timeout: Option<Duration>) -> (i32, String) {
+    let script = TempFile::script(&format!("headless-{}", name), script);
+    let options = RunOptions {
+        elf_path: script.path_string(),
+        elf_args: String::new(),
+        timeout,
+    };
+    let mut out = Vec::new();
+    let code = run_with_path(PathBuf::from(FAKE_EMULATOR), &options, &mut out).await;
+    (code, String::from_utf8(out).unwrap())
+}
+
+#[tokio::test]
+async fn streams_stdout_and_exits_with_program_code() {
+    let (code, out) = run(
+        "exit",
+        "ready\n@expect cmd:start\nstdout:hello\\n\nstdout:world\\n\nexit:3:1000\n@expect cmd:stop\n",
+        None,
+    )
+    .await;
+
+    assert_eq!(code, 3);
+    assert_eq!(out, "hello\nworld\n");
+}
+
+#[tokio::test]
+async fn reports_crash() {
+    let (code, _) = run("crash", "ready\ncrash:100:illegal instruction\n", None).await;
+
+    assert_eq!(code, EXIT_CRASHED);
+}
+
+#[tokio::test]
+async fn stops_on_timeout() {
+    let (code, out) = run(
+        "timeout",
+        "ready\nstdout:partial\n@expect cmd:stop\n",
+        Some(Duration::from_millis(500)),
+    )
+    .await;
+
+    assert_eq!(code, EXIT_TIMEOUT);
+    assert_eq!(out, "partial");
+}
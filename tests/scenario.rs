@@ -0,0 +1,80 @@
+mod common;
+
+use common::{TempFile, FAKE_EMULATOR};
+use h8pks::{headless::RunOptions, scenario};
+use std::{path::PathBuf, time::Duration};
+
+async fn run(name: &str, emulator_script: &str, scenario: &str) -> scenario::Report {
+    let script = TempFile::script(&format!("scenario-{}", name), emulator_script);
+    let options = RunOptions {
+        elf_path: script.path_string(),
+        elf_args: String::new(),
+        timeout: Some(Duration::from_secs(10)),
+    };
+    let scenario = scenario::Scenario::parse(scenario).unwrap();
+    scenario::run_with_path(PathBuf::from(FAKE_EMULATOR), &scenario, &options).await
+}
+
+#[tokio::test]
+async fn drives_switches_and_checks_expectations() {
+    let report = run(
+        "pass",
+        "@capabilities ioport,sync\n\
+         ready\n\
+         @expect ioport:5:3\n\
+         @expect cmd:start\n\
+         sync:12000000\n\
+         @expect ioport:5:b\n\
+         ioport:b:f0:15000000\n\
+         ioport:4:31:20000000\n\
+         stdout:hello\\n\n\
+         sync:24000000\n\
+         @expect cmd:stop\n",
+        r#"
+        [[stimulus]]
+        at = "0.5s"
+        toggle_switch = 1
+        on = true
+
+        [[expect]]
+        at = "1s"
+        port_b = 0xf0
+
+        [[expect]]
+        at = "1.01s"
+        seven_seg = "3   "
+
+        [[expect]]
+        stdout = "hello"
+        "#,
+    )
+    .await;
+
+    assert!(report.passed(), "{}", report);
+    assert_eq!(report.results.len(), 3);
+}
+
+#[tokio::test]
+async fn reports_failed_expectations() {
+    let report = run(
+        "fail",
+        "@capabilities ioport,sync\n\
+         ready\n\
+         ioport:b:0f:15000000\n\
+         exit:0:50000000\n",
+        r#"
+        [[expect]]
+        at = "1s"
+        port_b = 0xf0
+
+        [[expect]]
+        at = "2s"
+        port_b = 0x0f
+        "#,
+    )
+    .await;
+
+    assert!(!report.passed());
+    let outcomes: Vec<_> = report.results.iter().map(|r| r.outcome.clone()).collect();
+    assert_eq!(outcomes, vec![Err("was 0x0f".to_string()), Ok(())]);
+}